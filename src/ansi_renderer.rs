@@ -5,53 +5,263 @@
 //! ANSI renderer for pulldown-cmark.
 
 use std::borrow::Cow;
+use std::cmp;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
 
+use ansi_term::ANSIStrings;
 use html2runes;
-use pulldown_cmark::{Event, Tag};
+use pulldown_cmark::{Event, Parser, Tag, Alignment};
 use pulldown_cmark::Event::{Start, End, Text, Html, InlineHtml, SoftBreak, HardBreak,
                             FootnoteReference};
 
+use syntect::dumps;
 use syntect::easy::HighlightLines;
 use syntect::parsing::SyntaxSet;
 use syntect::highlighting;
 use syntect::parsing::syntax_definition::SyntaxDefinition;
 
-use super::dombox::{DomBox, BorderType, DomColor, TermColor, BoxKind, split_at_in_place};
-use super::OutputKind;
+use super::dombox::{DomBox, BorderType, DomColor, TermColor, BoxKind, TextAlign,
+                    split_at_in_place};
+use super::{BorderStyle, Color, HeaderFrame, HeaderStyle, Heading, Numbering, OutputKind,
+            ReferencePlacement, RenderError, RenderOptions, Rendered};
+use super::code_info::CodeInfo;
 
-pub fn push_ansi<'a, I: Iterator<Item = Event<'a>>>(
+// bullets of unordered lists, by nesting depth
+const BULLETS: [&'static str; 3] = ["•", "◦", "▪"];
+
+// events of a parser, with the bytes of the text each one was read from
+pub struct SourceEvents<'a> {
+    text: &'a str,
+    parser: Parser<'a>,
+}
+
+impl<'a> SourceEvents<'a> {
+    pub fn new(text: &'a str, parser: Parser<'a>) -> SourceEvents<'a> {
+        SourceEvents {
+            text: text,
+            parser: parser,
+        }
+    }
+}
+
+impl<'a> Iterator for SourceEvents<'a> {
+    type Item = (Event<'a>, Range<usize>);
+
+    fn next(&mut self) -> Option<(Event<'a>, Range<usize>)> {
+        let mut start = self.parser.get_offset();
+        let event = self.parser.next()?;
+        // inline events are queued, so the parser may already be past them
+        let end = cmp::max(self.parser.get_offset(), start);
+        // blocks start after the blank lines before them
+        if let Start(_) = event {
+            let skipped = &self.text[start..end];
+            start += skipped.len() - skipped.trim_start().len();
+        }
+        Some((event, start..end))
+    }
+}
+
+// the tree of the document, or of its table of contents only, not laid out
+// yet
+pub fn build<'a, I: Iterator<Item = (Event<'a>, Range<usize>)>>(
     iter: I,
+    opts: &RenderOptions,
+    toc_only: bool,
+) -> Result<DomBox<'a>, RenderError> {
+    let syntaxes = load_syntaxes(opts)?;
+    let themes = load_themes(opts)?;
+    let theme = match themes.themes.get(&opts.theme) {
+        Some(theme) => theme,
+        None => return Err(RenderError::UnknownTheme(opts.theme.clone())),
+    };
+    let default_syntax = match opts.default_language {
+        Some(ref lang) => {
+            match syntaxes.find_syntax_by_token(lang) {
+                Some(syntax) => Some(syntax),
+                None => return Err(RenderError::UnknownLanguage(lang.clone())),
+            }
+        }
+        None => None,
+    };
+    let mut ctx = Ctx::new(iter, opts, &syntaxes, theme, default_syntax);
+    Ok(if toc_only {
+        ctx.build_toc(opts.width)
+    } else {
+        ctx.build(opts.width)
+    })
+}
+
+// lay out the tree for `width` columns. When it can't be, a plain copy of
+// it is laid out instead and returned.
+pub fn layout<'a>(root: &mut DomBox<'a>, width: u16) -> Result<Option<DomBox<'a>>, RenderError> {
+    root.set_width(width);
+    match root.layout() {
+        Ok(()) => Ok(None),
+        // degrade to the bare text rather than failing
+        Err(_) => {
+            let mut flat = root.flatten();
+            flat.set_width(width);
+            flat.layout()?;
+            Ok(Some(flat))
+        }
+    }
+}
+
+// render the whole of an already laid out tree
+pub fn render<'a>(root: &DomBox<'a>, kind: &OutputKind) -> Result<Rendered, RenderError> {
+    let ansi_strings = root.render_lines(0, root.size.height_plus_border(), kind)?;
+    Ok(Rendered {
+        text: ANSIStrings(&ansi_strings).to_string(),
+        headings: headings(root),
+    })
+}
+
+// render some lines of an already laid out tree
+pub fn render_lines<'a>(
+    root: &DomBox<'a>,
+    start: u16,
+    end: u16,
+    kind: &OutputKind,
+) -> Result<String, RenderError> {
+    let ansi_strings = root.render_lines(start, end, kind)?;
+    Ok(ANSIStrings(&ansi_strings).to_string())
+}
+
+// same as `render`, but each top-level block is written to `out` as soon as
+// it's laid out, and only the blocks which can't be are made plain
+pub fn render_to<'a, W: Write>(
+    root: &mut DomBox<'a>,
     width: u16,
-    kind: OutputKind,
-) -> String {
-    let syntaxes = SyntaxSet::load_defaults_newlines();
-    let themes = highlighting::ThemeSet::load_defaults();
-    let mut ctx = Ctx::new(iter, &syntaxes, &themes);
-    let mut root = ctx.build(width);
-    root.layout();
-    let ansi_strings = root.render(&kind);
-
-    ansi_strings.into_iter().fold(String::new(), |s, ansi| {
-        s + &ansi.to_string()
+    kind: &OutputKind,
+    out: &mut W,
+) -> Result<(), RenderError> {
+    root.set_width(width);
+    root.render_children(kind, |strings| {
+        write!(out, "{}", ANSIStrings(&strings)).map_err(|err| {
+            RenderError::Output(err.to_string())
+        })
     })
 }
 
+// bundled themes, plus the ones found in the theme directory
+pub fn load_themes(opts: &RenderOptions) -> Result<highlighting::ThemeSet, RenderError> {
+    let mut themes = highlighting::ThemeSet::load_defaults();
+    if let Some(ref dir) = opts.theme_dir {
+        let extra = highlighting::ThemeSet::load_from_folder(dir).map_err(|err| {
+            RenderError::ThemeLoading(format!("{}: {:?}", dir.display(), err))
+        })?;
+        themes.themes.extend(extra.themes);
+    }
+    Ok(themes)
+}
+
+// bundled syntax definitions, plus the ones from the syntax directory and
+// dump file
+pub fn load_syntaxes(opts: &RenderOptions) -> Result<SyntaxSet, RenderError> {
+    let mut syntaxes = SyntaxSet::load_defaults_newlines();
+    if opts.syntax_dir.is_none() && opts.syntax_dump.is_none() {
+        return Ok(syntaxes);
+    }
+    if let Some(ref dir) = opts.syntax_dir {
+        syntaxes.load_syntaxes(dir, true).map_err(|err| {
+            RenderError::SyntaxLoading(format!("{}: {:?}", dir.display(), err))
+        })?;
+    }
+    if let Some(ref path) = opts.syntax_dump {
+        let extra: SyntaxSet = dumps::from_dump_file(path).map_err(|err| {
+            RenderError::SyntaxLoading(format!("{}: {:?}", path.display(), err))
+        })?;
+        for syntax in extra.syntaxes() {
+            syntaxes.add_syntax(syntax.clone());
+        }
+    }
+    syntaxes.link_syntaxes();
+    Ok(syntaxes)
+}
+
+// headers of a laid out tree
+pub fn headings<'a>(root: &DomBox<'a>) -> Vec<Heading> {
+    let mut headers = vec![];
+    root.headers(&mut headers);
+    headers
+        .into_iter()
+        .map(|(line, level, title)| {
+            Heading {
+                line: line as usize,
+                level: level,
+                title: title,
+            }
+        })
+        .collect()
+}
+
 struct Ctx<'a, 'b, I> {
     iter: I,
-    links: Option<DomBox<'a>>,
-    footnotes: Option<DomBox<'a>>,
+    opts: &'b RenderOptions,
+    // destinations and titles of the links, numbered from 1
+    references: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    // numbers of the references still to be listed
+    pending_references: Vec<usize>,
+    // how many build_dom calls are running, 1 at the top level of the document
+    depth: usize,
+    // lists being built, from the outermost one, and whether they're loose
+    lists: Vec<bool>,
+    // how many of them are ordered
+    ordered_lists: usize,
+    // depth of the content of the items being built
+    items: Vec<usize>,
+    // how many block quotes are being built
+    quotes: usize,
+    // how many headers of each level were seen since the last one above
+    header_counts: [usize; 6],
+    // level, number and title of the headers
+    toc: Vec<(usize, String, String)>,
+    // index in the root of the `[TOC]` paragraph
+    toc_at: Option<usize>,
+    // end of the last event read, and start of the last tag
+    offset: usize,
+    tag_start: usize,
+    // names of the referenced footnotes, numbered from 1, and how many times
+    // each is referenced
+    footnote_names: Vec<(Cow<'a, str>, usize)>,
+    footnote_bodies: Vec<(Cow<'a, str>, DomBox<'a>)>,
     syntaxes: &'b SyntaxSet,
-    themes: &'b highlighting::ThemeSet,
+    theme: &'b highlighting::Theme,
+    default_syntax: Option<&'b SyntaxDefinition>,
     syntax: Option<&'b SyntaxDefinition>,
-    pub theme: &'b str,
+    // language of the current code block, until its first line is seen
+    code_lang: Option<String>,
     highline: Option<HighlightLines<'b>>,
+    // whether links are wrapped in OSC 8 sequences
+    hyperlinks: bool,
+    table_head: bool,
+    table_row: usize,
+    table_col: usize,
 }
 
-impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
+impl<'a, 'b, I: Iterator<Item = (Event<'a>, Range<usize>)>> Ctx<'a, 'b, I> {
     fn tag_paragraph(&mut self, parent: &mut DomBox<'a>) {
+        // only the items of loose lists are made of paragraphs, code blocks
+        // or quotes don't make them loose
+        if self.items.last() == Some(&self.depth) {
+            if let Some(loose) = self.lists.last_mut() {
+                *loose = true;
+            }
+        }
+        let index = parent.children.len();
         let child = parent.add_block();
         self.build_dom(child);
         child.size.border.bottom = 1;
+        // the first `[TOC]` line of the document is where the table of
+        // contents goes
+        if self.opts.toc && self.depth == 1 && self.toc_at.is_none() &&
+            child.plain_text().trim() == "[TOC]"
+        {
+            child.children.clear();
+            self.toc_at = Some(index);
+        }
     }
 
     fn tag_rule(&mut self, parent: &mut DomBox<'a>) {
@@ -63,41 +273,117 @@ impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
     }
 
     fn tag_header(&mut self, parent: &mut DomBox<'a>, level: i32) {
+        let style = match self.opts.headers.get(level as usize - 1) {
+            Some(style) => style.clone(),
+            None => {
+                self.opts.headers.last().cloned().unwrap_or_else(|| {
+                    HeaderStyle::for_level(level as u8)
+                })
+            }
+        };
+        let number = self.number_header(level as usize);
         let child = parent.add_header(level as u8);
-        child.size.border.bottom = 1;
-
-        if level == 1 {
-            child.size.border.top = 1;
-            child.size.border.left = 1;
-            child.size.border.right = 1;
+        child.style.fg = dom_color(style.color);
+        child.style.bold = style.bold;
+        child.style.underline = style.underline;
+        let prefix = format!("{} ", number);
+        if self.opts.number_headers {
+            child.add_text(Cow::from(prefix.clone()));
+        }
+        self.build_dom(child);
+        {
+            let text = child.plain_text();
+            let title = if self.opts.number_headers {
+                text[prefix.len()..].trim().to_string()
+            } else {
+                text.trim().to_string()
+            };
+            self.toc.push((level as usize, number, title));
+        }
+        // only the text is underlined, not the frame
+        child.style.underline = false;
+        if style.uppercase {
+            child.uppercase();
         }
 
-        child.style.border_type = match level {
-            1 => BorderType::Thin,
-            2 => BorderType::Bold,
-            3 => BorderType::Double,
-            4 => BorderType::Thin,
-            5 => BorderType::Dash,
-            _ => BorderType::Thin,
+        child.size.border.bottom = 1;
+        child.style.border_type = match style.frame {
+            HeaderFrame::None => BorderType::Empty,
+            HeaderFrame::Rule(border) => border_type(border),
+            HeaderFrame::Box(border) => {
+                child.size.border.top = 1;
+                child.size.border.left = 1;
+                child.size.border.right = 1;
+                border_type(border)
+            }
         };
+    }
 
-        child.style.fg = DomColor::from_dark(TermColor::Purple);
-        self.build_dom(child);
+    // number of a header of `level`, like 1.2.1, leaving out the top levels
+    // without any header yet
+    fn number_header(&mut self, level: usize) -> String {
+        let level = cmp::min(level, self.header_counts.len());
+        self.header_counts[level - 1] += 1;
+        for count in &mut self.header_counts[level..] {
+            *count = 0;
+        }
+        let counts = &self.header_counts[..level];
+        let first = counts.iter().position(|&count| count > 0).unwrap_or(0);
+        counts[first..]
+            .iter()
+            .map(|count| count.to_string())
+            .collect::<Vec<_>>()
+            .join(".")
     }
 
+    // quotes get a bar of a different color for each level, the ones
+    // starting with a GitHub alert marker are boxed under a title instead
     fn tag_block_quote(&mut self, parent: &mut DomBox<'a>) {
-        {
-            let child = parent.add_block();
-            self.build_dom(child);
-            child.size.border.left = 1;
-            child.style.border_type = BorderType::Thin;
-            child.style.fg = DomColor::from_dark(TermColor::Cyan);
+        let depth = self.quotes;
+        let wrapper = parent.add_block();
+        wrapper.size.border.bottom = 1;
+        let child = wrapper.add_block();
+        self.quotes += 1;
+        self.build_dom(child);
+        self.quotes -= 1;
+        // the bar stops with the text, the wrapper spaces the quote
+        if let Some(last) = child.children.last_mut() {
+            last.size.border.bottom = 0;
+        }
+        child.style.border_type = BorderType::Thin;
+        match take_alert(child) {
+            Some((title, icon, color)) => {
+                let mut heading = DomBox::new_block();
+                {
+                    let text = heading.add_text(Cow::from(format!("{} {}", icon, title)));
+                    text.style.bold = true;
+                    text.style.fg = color.clone();
+                }
+                child.children.insert(0, heading);
+                child.size.border.top = 1;
+                child.size.border.bottom = 1;
+                child.size.border.left = 1;
+                child.size.border.right = 1;
+                child.style.extend = true;
+                child.style.fg = color;
+            }
+            None => {
+                child.size.border.left = 1;
+                child.style.fg = quote_color(depth);
+            }
         }
-        let newline = parent.add_block(); // XXX ugly
-        newline.add_text(Cow::from(""));
     }
 
     fn tag_code_block(&mut self, parent: &mut DomBox<'a>, info: Cow<'a, str>) {
+        let info = CodeInfo::parse(&info);
+        if let Some(caption) = info.caption() {
+            let child = parent.add_block();
+            child.style.extend = true;
+            child.style.bold = true;
+            child.style.fg = DomColor::from_light(TermColor::White);
+            child.style.bg = DomColor::from_light(TermColor::Black);
+            child.add_text(Cow::from(caption));
+        }
         {
             let indent = parent.style.indent;
             let child = parent.add_block();
@@ -105,47 +391,93 @@ impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
             child.style.bg = DomColor::from_dark(TermColor::Black);
             child.style.indent = indent + 2;
 
-            // NOTE: Just assume the language is rust if the language
-            // is omitted, since many docs don't have the 'rust' tag in
-            // code blocks
-            self.syntax = match self.syntaxes.find_syntax_by_token(&info) {
-                Some(syn) => Some(syn),
-                None => self.syntaxes.find_syntax_by_token("rust"),
-            };
-
-            if let Some(syn) = self.syntax {
-                self.highline = Some(HighlightLines::new(syn, &self.themes.themes[self.theme]));
-
-                self.build_dom(child);
-            }
+            // the syntax is picked once the first line is known
+            self.code_lang = Some(info.lang.clone());
+            self.build_dom(child);
+            add_code_gutter(child, &info, info.line_numbers || self.opts.line_numbers);
         }
         let newline = parent.add_block(); // XXX ugly
         newline.add_text(Cow::from(""));
     }
 
-    fn tag_list(&mut self, parent: &mut DomBox<'a>, start_opt: Option<usize>) {
-        match start_opt {
-            Some(start) => {
-                let child = parent.add_list(Some(start as u16));
-                self.build_dom(child);
-                child.size.border.bottom = 1;
+    // syntax of a code block: by name or extension from the info string, then
+    // from the first line, then the default language if any
+    fn find_syntax(&self, info: &str, first_line: &str) -> &'b SyntaxDefinition {
+        let syntaxes = self.syntaxes;
+        let token = info.trim();
+        if !token.is_empty() {
+            if let Some(syn) = syntaxes.find_syntax_by_token(token) {
+                return syn;
             }
-            None => {
-                let child = parent.add_list(None);
-                self.build_dom(child);
-                child.size.border.bottom = 1;
+            // info strings like `main.py`
+            if let Some(ext) = Path::new(token).extension().and_then(|ext| ext.to_str()) {
+                if let Some(syn) = syntaxes.find_syntax_by_extension(ext) {
+                    return syn;
+                }
             }
         }
+        if let Some(syn) = syntaxes.find_syntax_by_first_line(first_line) {
+            return syn;
+        }
+        match self.default_syntax {
+            Some(syn) => syn,
+            None => syntaxes.find_syntax_plain_text(),
+        }
+    }
+
+    fn tag_list(&mut self, parent: &mut DomBox<'a>, start_opt: Option<usize>) {
+        let child = parent.add_list(start_opt.map(|start| start as u16));
+        self.lists.push(false);
+        if start_opt.is_some() {
+            self.ordered_lists += 1;
+        }
+        self.build_dom(child);
+        if start_opt.is_some() {
+            self.ordered_lists -= 1;
+        }
+        let loose = self.lists.pop().unwrap_or(false);
+        // the items of loose lists are spaced by their paragraphs, nested
+        // lists by the items around them
+        if !loose && self.lists.is_empty() {
+            child.size.border.bottom = 1;
+        }
     }
 
     fn tag_item(&mut self, parent: &mut DomBox<'a>) {
+        // ordered lists are numbered once all their items are known
+        let symbol = match parent.kind {
+            BoxKind::List(None) => {
+                let depth = self.lists.len().saturating_sub(1);
+                Some(BULLETS[depth % BULLETS.len()])
+            }
+            _ => None,
+        };
         {
             let bullet = parent.add_bullet();
             bullet.style.fg = DomColor::from_light(TermColor::Yellow);
             bullet.size.border.right = 1;
+            if let Some(symbol) = symbol {
+                bullet.add_text(Cow::from(symbol));
+            }
+        }
+        let task = {
+            let child = parent.add_block();
+            self.items.push(self.depth + 1);
+            self.build_dom(child);
+            self.items.pop();
+            take_task_marker(child)
+        };
+        if let Some(checked) = task {
+            let index = parent.children.len() - 2;
+            let bullet = &mut parent.children[index];
+            bullet.children.clear();
+            if checked {
+                let mark = bullet.add_text(Cow::from("☑"));
+                mark.style.fg = DomColor::from_dark(TermColor::Green);
+            } else {
+                bullet.add_text(Cow::from("☐"));
+            }
         }
-        let child = parent.add_block();
-        self.build_dom(child);
     }
 
     fn tag_emphasis(&mut self, parent: &mut DomBox<'a>) {
@@ -167,22 +499,124 @@ impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
         self.build_dom(child);
     }
 
-    fn tag_link(&mut self, parent: &mut DomBox<'a>, dest: Cow<'a, str>) {
-        if let Some(mut links) = self.links.take() {
+    fn tag_link(&mut self, parent: &mut DomBox<'a>, dest: Cow<'a, str>, title: Cow<'a, str>) {
+        let link = dest.to_string();
+        // repeated destinations share the same number
+        let number = match self.references.iter().position(|r| r.0 == dest) {
+            Some(index) => index + 1,
+            None => {
+                self.references.push((dest, title));
+                self.references.len()
+            }
+        };
+        if !self.pending_references.contains(&number) {
+            self.pending_references.push(number);
+        }
+        {
+            let child = parent.add_inline();
+            child.style.underline = true;
+            child.style.fg = DomColor::from_dark(TermColor::Blue);
+            child.style.hyperlink = self.hyperlinks && is_absolute_url(&link);
+            child.style.link = Some(link);
+            self.build_dom(child);
+        }
+        let marker = parent.add_text(Cow::from(format!("[{}]", number)));
+        marker.style.fg = DomColor::from_dark(TermColor::Blue);
+        marker.style.underline = false;
+    }
+
+    // the markers are right-aligned, so that the items all start on the
+    // same column
+    fn number_items(&self, list: &mut DomBox<'a>, start: usize) {
+        let numbering = &self.opts.numbering;
+        let numbering = if numbering.is_empty() {
+            Numbering::Decimal
+        } else {
+            numbering[(self.ordered_lists - 1) % numbering.len()]
+        };
+        let count = list.children
+            .iter()
+            .filter(|child| if let BoxKind::ListBullet = child.kind { true } else { false })
+            .count();
+        let markers: Vec<String> = (start..start + count)
+            .map(|n| format!("{}.", numbering.format(n)))
+            .collect();
+        let width = markers.iter().map(|marker| marker.chars().count()).max().unwrap_or(0);
+        let mut markers = markers.into_iter();
+        for child in &mut list.children {
+            if let BoxKind::ListBullet = child.kind {
+                let marker = markers.next().unwrap_or_default();
+                if child.children.is_empty() {
+                    child.add_text(Cow::from(format!("{:>1$}", marker, width)));
+                } else if width > 1 {
+                    // the checkbox of a task item
+                    let padding = Cow::from(" ".repeat(width - 1));
+                    child.get_inline_container().prepend_text(padding);
+                }
+            }
+        }
+    }
+
+    // the table of contents replaces the `[TOC]` paragraph, or else starts
+    // the document
+    fn add_toc(&mut self, root: &mut DomBox<'a>) {
+        let index = match self.toc_at.take() {
+            Some(index) => {
+                root.children.remove(index);
+                index
+            }
+            None => 0,
+        };
+        if self.toc.is_empty() {
+            return;
+        }
+        let mut block = DomBox::new_block();
+        block.size.border.bottom = 1;
+        {
+            let heading = block.add_block();
+            let text = heading.add_text(Cow::from("Contents"));
+            text.style.bold = true;
+            text.style.fg = DomColor::from_dark(TermColor::Purple);
+        }
+        let mut i = 0;
+        while i < self.toc.len() {
+            add_toc_list(&mut block, &self.toc, &mut i, 0, self.opts.toc_numbers);
+        }
+        root.children.insert(index, block);
+    }
+
+    // list the links seen since the last list
+    fn add_references(&mut self, parent: &mut DomBox<'a>) {
+        if self.pending_references.is_empty() {
+            return;
+        }
+        self.pending_references.sort();
+        let block = parent.add_block();
+        block.size.border.bottom = 1;
+        {
+            let heading = block.add_text(Cow::from("References"));
+            heading.style.bold = true;
+            heading.style.fg = DomColor::from_dark(TermColor::Purple);
+        }
+        for number in self.pending_references.drain(..) {
+            let (ref dest, ref title) = self.references[number - 1];
+            block.add_break();
             {
-                let child = links.add_text(dest);
-                child.style.fg = DomColor::from_dark(TermColor::Blue);
-                child.style.underline = true;
+                let marker = block.add_text(Cow::from(format!("[{}] ", number)));
+                marker.style.fg = DomColor::from_dark(TermColor::Blue);
             }
             {
-                links.add_break();
+                let url = block.add_text(dest.clone());
+                url.style.fg = DomColor::from_dark(TermColor::Blue);
+                url.style.underline = true;
+                url.style.link = Some(dest.to_string());
+                url.style.hyperlink = self.hyperlinks && is_absolute_url(dest);
+            }
+            if !title.is_empty() {
+                let child = block.add_text(Cow::from(format!(" \"{}\"", title)));
+                child.style.italic = true;
             }
-            self.links = Some(links);
         }
-        let child = parent.add_inline();
-        child.style.underline = true;
-        child.style.fg = DomColor::from_dark(TermColor::Blue);
-        self.build_dom(child);
     }
 
     fn tag_image(&mut self, parent: &mut DomBox<'a>, dest: Cow<'a, str>, title: Cow<'a, str>) {
@@ -203,76 +637,282 @@ impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
 
     }
 
+    fn tag_table(&mut self, parent: &mut DomBox<'a>, aligns: Vec<Alignment>) {
+        let aligns = aligns
+            .into_iter()
+            .map(|align| match align {
+                Alignment::None | Alignment::Left => TextAlign::Left,
+                Alignment::Center => TextAlign::Center,
+                Alignment::Right => TextAlign::Right,
+            })
+            .collect();
+        let child = parent.add_block();
+        child.size.border.bottom = 1;
+        let table = child.add_table(aligns);
+        table.style.border_type = BorderType::Thin;
+        self.table_row = 0;
+        self.table_col = 0;
+        self.build_dom(table);
+    }
+
+    fn tag_table_head(&mut self) {
+        self.table_head = true;
+        self.table_row = 0;
+        self.table_col = 0;
+    }
+
+    fn tag_table_row(&mut self) {
+        self.table_head = false;
+        self.table_row += 1;
+        self.table_col = 0;
+    }
+
+    fn tag_table_cell(&mut self, parent: &mut DomBox<'a>) {
+        let child = parent.add_table_item(self.table_col, self.table_row);
+        if self.table_head {
+            child.style.bold = true;
+            child.style.fg = DomColor::from_light(TermColor::Yellow);
+        }
+        self.build_dom(child);
+        child.trim_text();
+        self.table_col += 1;
+    }
+
+    // the body is kept aside, to be shown in the footnotes section
     fn tag_footnote(&mut self, _parent: &mut DomBox<'a>, name: Cow<'a, str>) {
-        if let Some(mut footnotes) = self.footnotes.take() {
-            {
-                let child = footnotes.add_text(name);
-                child.style.fg = DomColor::from_dark(TermColor::Green);
-                child.style.underline = true;
+        let mut body = DomBox::new_block();
+        self.build_dom(&mut body);
+        body.fill_spans();
+        self.footnote_bodies.push((name, body));
+    }
+
+    // footnotes are numbered in the order they're first referenced
+    fn add_footnote_reference(&mut self, parent: &mut DomBox<'a>, name: Cow<'a, str>) {
+        let number = match self.footnote_names.iter().position(|&(ref n, _)| *n == name) {
+            Some(index) => {
+                self.footnote_names[index].1 += 1;
+                index + 1
+            }
+            None => {
+                self.footnote_names.push((name, 1));
+                self.footnote_names.len()
+            }
+        };
+        let text = if self.opts.kind == OutputKind::Plain {
+            format!("[^{}]", number)
+        } else {
+            superscript(number)
+        };
+        let child = parent.add_text(Cow::from(text));
+        child.style.fg = DomColor::from_dark(TermColor::Green);
+    }
+
+    // markers at the end of a footnote, one for each place it's referenced
+    // from, and numbered when there are several
+    fn add_back_references(&self, body: &mut DomBox<'a>, count: usize) {
+        // after the text of the last paragraph, or on a line of their own
+        let after_text = match body.children.last() {
+            Some(last) => {
+                match last.children.last() {
+                    Some(&DomBox { kind: BoxKind::InlineContainer, .. }) => true,
+                    _ => false,
+                }
+            }
+            None => false,
+        };
+        let line = if after_text {
+            body.children.last_mut().unwrap().get_inline_container()
+        } else {
+            body.add_block().get_inline_container()
+        };
+        for i in 1..count + 1 {
+            let mut text = String::from(if line.children.is_empty() { "↩" } else { " ↩" });
+            if count > 1 {
+                if self.opts.kind == OutputKind::Plain {
+                    text.push_str(&i.to_string());
+                } else {
+                    text.push_str(&superscript(i));
+                }
+            }
+            let child = line.add_text(Cow::from(text));
+            child.style.fg = DomColor::from_dark(TermColor::Green);
+        }
+    }
+
+    fn add_footnotes(&mut self, parent: &mut DomBox<'a>) {
+        if self.footnote_names.is_empty() && self.footnote_bodies.is_empty() {
+            return;
+        }
+        let section = parent.add_block();
+        self.tag_rule(section);
+        {
+            let heading = section.add_block();
+            heading.size.border.bottom = 1;
+            let text = heading.add_text(Cow::from("Footnotes"));
+            text.style.bold = true;
+            text.style.fg = DomColor::from_dark(TermColor::Purple);
+        }
+        let mut warnings = vec![];
+        {
+            let list = section.add_list(None);
+            for (i, &(ref name, count)) in self.footnote_names.iter().enumerate() {
+                match self.footnote_bodies.iter().position(|&(ref n, _)| n == name) {
+                    Some(index) => {
+                        let mut body = self.footnote_bodies.remove(index).1;
+                        self.add_back_references(&mut body, count);
+                        {
+                            let bullet = list.add_bullet();
+                            bullet.style.fg = DomColor::from_dark(TermColor::Green);
+                            bullet.size.border.right = 1;
+                            bullet.add_text(Cow::from(format!("{}.", i + 1)));
+                        }
+                        list.swallow(body);
+                    }
+                    None => {
+                        warnings.push(format!(
+                            "warning: footnote [^{}] is referenced but not defined",
+                            name
+                        ))
+                    }
+                }
+            }
+        }
+        for &(ref name, _) in &self.footnote_bodies {
+            warnings.push(format!(
+                "warning: footnote [^{}] is defined but never referenced",
+                name
+            ));
+        }
+        if !warnings.is_empty() {
+            let block = section.add_block();
+            block.size.border.bottom = 1;
+            block.style.fg = DomColor::from_light(TermColor::Yellow);
+            for (i, warning) in warnings.into_iter().enumerate() {
+                if i > 0 {
+                    block.add_break();
+                }
+                block.add_text(Cow::from(warning));
             }
-            self.build_dom(&mut footnotes);
-            self.footnotes = Some(footnotes);
         }
     }
 }
 
-impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
-    pub fn new(iter: I, syntaxes: &'b SyntaxSet, themes: &'b highlighting::ThemeSet) -> Self {
+impl<'a, 'b, I: Iterator<Item = (Event<'a>, Range<usize>)>> Ctx<'a, 'b, I> {
+    pub fn new(
+        iter: I,
+        opts: &'b RenderOptions,
+        syntaxes: &'b SyntaxSet,
+        theme: &'b highlighting::Theme,
+        default_syntax: Option<&'b SyntaxDefinition>,
+    ) -> Self {
         Ctx {
             iter: iter,
-            links: None,
-            footnotes: None,
+            opts: opts,
+            references: vec![],
+            pending_references: vec![],
+            depth: 0,
+            lists: vec![],
+            items: vec![],
+            ordered_lists: 0,
+            quotes: 0,
+            header_counts: [0; 6],
+            toc: vec![],
+            toc_at: None,
+            offset: 0,
+            tag_start: 0,
+            footnote_names: vec![],
+            footnote_bodies: vec![],
             syntaxes: syntaxes,
-            themes: themes,
+            theme: theme,
+            default_syntax: default_syntax,
             syntax: None,
-            theme: "base16-eighties.dark",
+            code_lang: None,
             highline: None,
+            hyperlinks: opts.hyperlinks.enabled(),
+            table_head: false,
+            table_row: 0,
+            table_col: 0,
         }
     }
 
     fn build(&mut self, width: u16) -> DomBox<'a> {
-        self.links = Some(DomBox::new_block());
-        self.footnotes = Some(DomBox::new_block());
         let mut root = DomBox::new_root(width);
         self.build_dom(&mut root);
-        if let Some(links) = self.links.take() {
-            root.swallow(links);
-        }
-        if let Some(footnotes) = self.footnotes.take() {
-            root.swallow(footnotes);
+        // before the sections which aren't from the text
+        root.fill_spans();
+        if self.opts.toc {
+            self.add_toc(&mut root);
         }
+        self.add_references(&mut root);
+        self.add_footnotes(&mut root);
         root
     }
 
+    // a document with only the table of contents of the text
+    fn build_toc(&mut self, width: u16) -> DomBox<'a> {
+        let mut text = DomBox::new_root(width);
+        self.build_dom(&mut text);
+        // a `[TOC]` line is in the text, not in this document
+        self.toc_at = None;
+        let mut root = DomBox::new_root(width);
+        self.add_toc(&mut root);
+        root
+    }
+
+    // the box of a tag spans from its start to its end, the boxes added for
+    // the other events span the event
     fn build_dom(&mut self, parent: &mut DomBox<'a>) {
+        let start = self.tag_start;
+        self.depth += 1;
         loop {
             match self.iter.next() {
-                Some(event) => {
-                    match event {
-                        Start(tag) => self.on_tag_start(parent, tag),
-                        End(tag) => {
-                            let should_end = self.on_tag_end(parent, tag);
-                            if should_end {
-                                break;
-                            }
+                Some((event, span)) => {
+                    self.offset = span.end;
+                    let should_end = match event {
+                        Start(tag) => {
+                            self.tag_start = span.start;
+                            self.on_tag_start(parent, tag);
+                            false
+                        }
+                        End(tag) => self.on_tag_end(parent, tag),
+                        Text(text) => {
+                            self.on_text(parent, text);
+                            false
+                        }
+                        Html(html) | InlineHtml(html) => {
+                            add_html(parent, html);
+                            false
                         }
-                        Text(text) => self.on_text(parent, text),
-                        Html(html) | InlineHtml(html) => add_html(parent, html),
                         SoftBreak | HardBreak => {
                             parent.add_break();
+                            false
+                        }
+                        FootnoteReference(name) => {
+                            self.add_footnote_reference(parent, name);
+                            false
                         }
-                        FootnoteReference(name) => add_footnote(parent, name),
+                    };
+                    parent.set_new_spans(&(span.start..self.offset));
+                    if should_end {
+                        break;
                     }
                 }
                 None => break,
             }
         }
+        parent.span = Some(start..self.offset);
+        self.depth -= 1;
     }
     fn on_tag_start(&mut self, parent: &mut DomBox<'a>, tag: Tag<'a>) {
         match tag {
             Tag::Paragraph => self.tag_paragraph(parent),
             Tag::Rule => self.tag_rule(parent),
-            Tag::Header(level) => self.tag_header(parent, level),
+            Tag::Header(level) => {
+                if self.depth == 1 && self.opts.references == ReferencePlacement::EachSection {
+                    self.add_references(parent);
+                }
+                self.tag_header(parent, level)
+            }
             Tag::BlockQuote => self.tag_block_quote(parent),
             Tag::CodeBlock(info) => self.tag_code_block(parent, info),
             Tag::List(start_opt) => self.tag_list(parent, start_opt),
@@ -280,11 +920,13 @@ impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
             Tag::Emphasis => self.tag_emphasis(parent),
             Tag::Strong => self.tag_strong(parent),
             Tag::Code => self.tag_code(parent),
-            Tag::Link(dest, _) => self.tag_link(parent, dest),
+            Tag::Link(dest, title) => self.tag_link(parent, dest, title),
             Tag::Image(dest, title) => self.tag_image(parent, dest, title),
             Tag::FootnoteDefinition(name) => self.tag_footnote(parent, name),
-
-            Tag::Table(_) | Tag::TableHead | Tag::TableRow | Tag::TableCell => {}
+            Tag::Table(aligns) => self.tag_table(parent, aligns),
+            Tag::TableHead => self.tag_table_head(),
+            Tag::TableRow => self.tag_table_row(),
+            Tag::TableCell => self.tag_table_cell(parent),
         }
     }
 
@@ -299,44 +941,38 @@ impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
             Tag::Code |
             Tag::Link(_, _) |
             Tag::Image(_, _) |
-            Tag::FootnoteDefinition(_) => true,
+            Tag::FootnoteDefinition(_) |
+            Tag::Table(_) |
+            Tag::TableCell => true,
 
             Tag::CodeBlock(_) => {
                 self.highline = None;
                 self.syntax = None;
+                self.code_lang = None;
                 true
             }
-            Tag::List(None) => {
-                for child in &mut parent.children {
-                    {
-                        if let BoxKind::ListBullet = child.kind {
-                            child.add_text(Cow::from("*"));
-                        }
-                    }
-                }
-                true
-            }
+            Tag::List(None) => true,
             Tag::List(Some(start)) => {
-                let mut i = start;
-                // TODO resize all bullets like the last one
-                //let end = start + node.children.len() / 2;
-                for child in &mut parent.children {
-                    {
-                        if let BoxKind::ListBullet = child.kind {
-                            child.add_text(Cow::from(i.to_string()));
-                            i += 1;
-                        }
-                    }
-                }
+                self.number_items(parent, start);
                 true
             }
 
-            Tag::Rule | Tag::Table(_) | Tag::TableHead | Tag::TableRow | Tag::TableCell => false,
+            Tag::TableHead | Tag::TableRow => {
+                parent.fill_table_row(self.table_row);
+                false
+            }
+
+            Tag::Rule => false,
         }
     }
 
 
     fn on_text(&mut self, parent: &mut DomBox<'a>, text: Cow<'a, str>) {
+        if let Some(lang) = self.code_lang.take() {
+            let syntax = self.find_syntax(&lang, text.lines().next().unwrap_or(""));
+            self.syntax = Some(syntax);
+            self.highline = Some(HighlightLines::new(syntax, self.theme));
+        }
         if let Some(ref mut h) = self.highline {
             add_highlighted_text(parent, text, h)
         } else {
@@ -346,6 +982,150 @@ impl<'a, 'b, I: Iterator<Item = Event<'a>>> Ctx<'a, 'b, I> {
 }
 
 
+fn dom_color(color: Color) -> DomColor {
+    match color {
+        Color::Indexed(idx) => DomColor::from_index(idx),
+        Color::Rgb(r, g, b) => DomColor::from_color(r, g, b),
+    }
+}
+
+fn border_type(border: BorderStyle) -> BorderType {
+    match border {
+        BorderStyle::Thin => BorderType::Thin,
+        BorderStyle::Bold => BorderType::Bold,
+        BorderStyle::Double => BorderType::Double,
+        BorderStyle::Dashed => BorderType::Dash,
+    }
+}
+
+// color of the bar of block quotes, by nesting depth
+fn quote_color(depth: usize) -> DomColor {
+    match depth % 4 {
+        0 => DomColor::from_dark(TermColor::Cyan),
+        1 => DomColor::from_dark(TermColor::Green),
+        2 => DomColor::from_dark(TermColor::Purple),
+        _ => DomColor::from_dark(TermColor::Yellow),
+    }
+}
+
+// the headers from `*i` on, until one above the first, as nested lists
+fn add_toc_list<'a>(
+    parent: &mut DomBox<'a>,
+    headers: &[(usize, String, String)],
+    i: &mut usize,
+    depth: usize,
+    numbered: bool,
+) {
+    let level = headers[*i].0;
+    let list = parent.add_list(None);
+    while *i < headers.len() && headers[*i].0 >= level {
+        let (ref header_level, ref number, ref title) = headers[*i];
+        if *header_level > level {
+            // under the previous header
+            let item = list.children.last_mut().unwrap();
+            add_toc_list(item, headers, i, depth + 1, numbered);
+            continue;
+        }
+        {
+            // numbers would make a wider bullet at each level, they go with
+            // the titles instead
+            let bullet = list.add_bullet();
+            bullet.style.fg = DomColor::from_light(TermColor::Yellow);
+            bullet.size.border.right = 1;
+            bullet.add_text(Cow::from(if numbered && depth > 0 {
+                " "
+            } else if numbered {
+                ""
+            } else {
+                BULLETS[depth % BULLETS.len()]
+            }));
+        }
+        let item = list.add_block();
+        if numbered {
+            let text = item.add_text(Cow::from(format!("{} ", number)));
+            text.style.fg = DomColor::from_light(TermColor::Yellow);
+        }
+        item.add_text(Cow::from(title.clone()));
+        *i += 1;
+    }
+}
+
+// title, icon and color of the GitHub alert a marker like `[!NOTE]` starts
+fn alert_style(marker: &str) -> Option<(&'static str, &'static str, DomColor)> {
+    match &marker.to_uppercase()[..] {
+        "[!NOTE]" => Some(("Note", "ℹ", DomColor::from_dark(TermColor::Blue))),
+        "[!TIP]" => Some(("Tip", "💡", DomColor::from_dark(TermColor::Green))),
+        "[!IMPORTANT]" => Some(("Important", "❗", DomColor::from_dark(TermColor::Purple))),
+        "[!WARNING]" => Some(("Warning", "⚠", DomColor::from_dark(TermColor::Yellow))),
+        "[!CAUTION]" => Some(("Caution", "⛔", DomColor::from_dark(TermColor::Red))),
+        _ => None,
+    }
+}
+
+// the alert marker alone on the first line of a block quote is removed, and
+// tells how the alert looks
+fn take_alert<'a>(quote: &mut DomBox<'a>) -> Option<(&'static str, &'static str, DomColor)> {
+    let alert = {
+        let line = quote.children.first()?.children.first()?;
+        match line.kind {
+            BoxKind::InlineContainer => alert_style(line.plain_text().trim())?,
+            _ => return None,
+        }
+    };
+    let empty = {
+        let paragraph = &mut quote.children[0];
+        paragraph.children.remove(0);
+        let is_break = match paragraph.children.first() {
+            Some(child) => if let BoxKind::Break = child.kind { true } else { false },
+            None => false,
+        };
+        if is_break {
+            paragraph.children.remove(0);
+        }
+        paragraph.children.is_empty()
+    };
+    if empty {
+        quote.children.remove(0);
+    }
+    Some(alert)
+}
+
+// the `[ ]` or `[x]` starting the text of a task list item is removed, and
+// tells whether the task is done
+fn take_task_marker<'a>(item: &mut DomBox<'a>) -> Option<bool> {
+    // first line of the item, or of its first paragraph when it's loose
+    let mut line = item.children.first_mut()?;
+    if let BoxKind::Block = line.kind {
+        line = line.children.first_mut()?;
+    }
+    let text = line.leading_text()?;
+    // the marker is followed by a space, or is the whole text
+    let (checked, len) = match text.kind {
+        BoxKind::Text(ref text) => {
+            let checked = if text.starts_with("[ ]") {
+                false
+            } else if text.starts_with("[x]") || text.starts_with("[X]") {
+                true
+            } else {
+                return None;
+            };
+            match &text[3..] {
+                "" => (checked, 3),
+                rest if rest.starts_with(' ') => (checked, 4),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    if let BoxKind::Text(ref mut text) = text.kind {
+        *text = split_at_in_place(text, len);
+    }
+    if let Some(ref mut span) = text.span {
+        span.start = cmp::min(span.start + len, span.end);
+    }
+    Some(checked)
+}
+
 fn add_plain_text<'a>(parent: &mut DomBox<'a>, mut text: Cow<'a, str>) {
     let mut add_break = false;
     if text.len() > 0 {
@@ -370,42 +1150,91 @@ fn add_highlighted_text<'a, 'b>(
     text: Cow<'a, str>,
     highline: &mut HighlightLines<'b>,
 ) {
-    match text {
+    let ranges: Vec<(highlighting::Style, Cow<'a, str>)> = match text {
         Cow::Borrowed(text) => {
-            let ranges = highline.highlight(&text);
-            for (style, mut text) in ranges {
-                let mut add_break = false;
-                if text.len() > 0 {
-                    // check if text ends with a newline
-                    let bytes = text.as_bytes();
-                    if bytes[bytes.len() - 1] == 10 {
-                        add_break = true;
-                    }
-                }
-                if add_break {
-                    text = &text[..text.len() - 1];
-                }
-                {
-                    let child = parent.add_text(Cow::Borrowed(text));
-                    child.style.fg = DomColor::from_color(
-                        style.foreground.r,
-                        style.foreground.g,
-                        style.foreground.b,
-                    );
-                    child.style.bold |= style.font_style.intersects(highlighting::FONT_STYLE_BOLD);
-                    child.style.italic |=
-                        style.font_style.intersects(highlighting::FONT_STYLE_ITALIC);
-                    child.style.underline |= style.font_style.intersects(
-                        highlighting::FONT_STYLE_UNDERLINE,
-                    );
-                }
-                if add_break {
-                    parent.add_break();
-                }
+            highline
+                .highlight(text)
+                .into_iter()
+                .map(|(style, text)| (style, Cow::Borrowed(text)))
+                .collect()
+        }
+        Cow::Owned(text) => {
+            highline
+                .highlight(&text)
+                .into_iter()
+                .map(|(style, text)| (style, Cow::Owned(text.to_string())))
+                .collect()
+        }
+    };
+    for (style, mut text) in ranges {
+        let mut add_break = false;
+        if text.len() > 0 {
+            // check if text ends with a newline
+            let bytes = text.as_bytes();
+            if bytes[bytes.len() - 1] == 10 {
+                add_break = true;
             }
         }
-        Cow::Owned(_text) => {
-            unimplemented!();
+        if add_break {
+            let pos = text.len() - 1;
+            split_at_in_place(&mut text, pos);
+        }
+        {
+            let child = parent.add_text(text);
+            child.style.fg = DomColor::from_color(
+                style.foreground.r,
+                style.foreground.g,
+                style.foreground.b,
+            );
+            child.style.bold |= style.font_style.intersects(highlighting::FONT_STYLE_BOLD);
+            child.style.italic |= style.font_style.intersects(highlighting::FONT_STYLE_ITALIC);
+            child.style.underline |= style.font_style.intersects(
+                highlighting::FONT_STYLE_UNDERLINE,
+            );
+        }
+        if add_break {
+            parent.add_break();
+        }
+    }
+}
+
+// prefix each line of a code block with its number, and emphasize the
+// highlighted ones
+fn add_code_gutter<'a>(block: &mut DomBox<'a>, info: &CodeInfo, numbers: bool) {
+    if !numbers && info.highlighted.is_empty() {
+        return;
+    }
+    let count = block
+        .children
+        .iter()
+        .filter(|child| if let BoxKind::InlineContainer = child.kind {
+            true
+        } else {
+            false
+        })
+        .count();
+    let digits = count.to_string().len();
+    let mut n = 0;
+    for line in &mut block.children {
+        if let BoxKind::InlineContainer = line.kind {
+        } else {
+            continue;
+        }
+        n += 1;
+        let highlighted = info.is_highlighted(n);
+        if highlighted {
+            for piece in &mut line.children {
+                piece.style.bg = DomColor::from_grey(0x40);
+            }
+        }
+        if numbers {
+            let gutter = line.prepend_text(Cow::from(format!("{:>1$} │ ", n, digits)));
+            gutter.style.bold = highlighted;
+            gutter.style.fg = if highlighted {
+                DomColor::from_light(TermColor::Yellow)
+            } else {
+                DomColor::from_light(TermColor::Black)
+            };
         }
     }
 }
@@ -416,8 +1245,32 @@ fn add_html<'a>(parent: &mut DomBox<'a>, html: Cow<'a, str>) {
     child.style.fg = DomColor::from_light(TermColor::Red);
 }
 
-fn add_footnote<'a>(parent: &mut DomBox<'a>, name: Cow<'a, str>) {
-    let child = parent.add_text(name);
-    child.style.fg = DomColor::from_dark(TermColor::Green);
-    child.style.underline = true;
+// whether `url` starts with a scheme, terminals can't follow anything else
+fn is_absolute_url(url: &str) -> bool {
+    match url.find(':') {
+        Some(end) => {
+            let scheme = &url[..end];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+            scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+        None => false,
+    }
+}
+
+fn superscript(n: usize) -> String {
+    n.to_string()
+        .chars()
+        .map(|c| match c {
+            '0' => '⁰',
+            '1' => '¹',
+            '2' => '²',
+            '3' => '³',
+            '4' => '⁴',
+            '5' => '⁵',
+            '6' => '⁶',
+            '7' => '⁷',
+            '8' => '⁸',
+            _ => '⁹',
+        })
+        .collect()
 }
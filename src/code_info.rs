@@ -0,0 +1,186 @@
+// Copyright 2016 Xavier Bestel -  All rights reserved.
+//
+// GPL goes here
+
+//! Parsing of fenced code block info strings.
+//!
+//! Handles rustdoc style flags (`rust,no_run`) as well as attributes as
+//! found in static site generators (`python title="app.py" {3-5} linenums`).
+
+// flags understood by rustdoc, which all imply a Rust code block
+const RUSTDOC_FLAGS: &'static [&'static str] = &[
+    "ignore",
+    "no_run",
+    "should_panic",
+    "compile_fail",
+    "test_harness",
+    "allow_fail",
+    "edition2015",
+    "edition2018",
+];
+
+const LINE_NUMBER_FLAGS: &'static [&'static str] = &[
+    "linenos",
+    "linenums",
+    "line-numbers",
+    "numberLines",
+    "showLineNumbers",
+];
+
+#[derive(Debug, Default, Clone)]
+pub struct CodeInfo {
+    pub lang: String,
+    pub title: Option<String>,
+    pub flags: Vec<String>,
+    pub line_numbers: bool,
+    // inclusive ranges of line numbers, starting at 1
+    pub highlighted: Vec<(usize, usize)>,
+}
+
+impl CodeInfo {
+    pub fn parse(info: &str) -> CodeInfo {
+        let mut code_info = CodeInfo::default();
+        for (i, token) in tokenize(info).into_iter().enumerate() {
+            if token.starts_with('{') {
+                code_info.parse_ranges(token.trim_matches(|c| c == '{' || c == '}'));
+            } else if token.starts_with("title=") {
+                code_info.title = Some(token["title=".len()..].to_string());
+            } else if RUSTDOC_FLAGS.contains(&&token[..]) {
+                code_info.flags.push(token);
+            } else if LINE_NUMBER_FLAGS.contains(&&token[..]) {
+                code_info.line_numbers = true;
+            } else if i == 0 {
+                code_info.lang = token;
+            }
+        }
+        if code_info.lang.is_empty() && !code_info.flags.is_empty() {
+            code_info.lang = "rust".to_string();
+        }
+        code_info
+    }
+
+    // text of the bar drawn above the code, if any
+    pub fn caption(&self) -> Option<String> {
+        let mut caption = self.title.clone().unwrap_or_default();
+        for flag in &self.flags {
+            if !caption.is_empty() {
+                caption.push(' ');
+            }
+            caption.push('[');
+            caption.push_str(flag);
+            caption.push(']');
+        }
+        if caption.is_empty() {
+            None
+        } else {
+            Some(caption)
+        }
+    }
+
+    pub fn is_highlighted(&self, line: usize) -> bool {
+        self.highlighted.iter().any(|&(start, end)| {
+            line >= start && line <= end
+        })
+    }
+
+    fn parse_ranges(&mut self, ranges: &str) {
+        for range in ranges.split(',') {
+            let mut bounds = range.splitn(2, '-').map(|n| n.trim().parse::<usize>());
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(start)), None) => self.highlighted.push((start, start)),
+                (Some(Ok(start)), Some(Ok(end))) => self.highlighted.push((start, end)),
+                _ => {}
+            }
+        }
+    }
+}
+
+// split on commas and spaces, except inside quotes and braces
+fn tokenize(info: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_quotes = false;
+    let mut in_braces = false;
+    for c in info.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '{' if !in_quotes => {
+                in_braces = true;
+                token.push(c);
+            }
+            '}' if !in_quotes => {
+                in_braces = false;
+                token.push(c);
+            }
+            ',' | ' ' | '\t' if !in_quotes && !in_braces => {
+                if !token.is_empty() {
+                    tokens.push(token);
+                    token = String::new();
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_title() {
+        let info = CodeInfo::parse(r#"python title="my app, v2.py" {3}"#);
+        assert_eq!(info.lang, "python");
+        assert_eq!(info.title, Some("my app, v2.py".to_string()));
+        assert_eq!(info.highlighted, vec![(3, 3)]);
+    }
+
+    #[test]
+    fn quoted_braces_are_text() {
+        let info = CodeInfo::parse(r#"js title="{1-2}""#);
+        assert_eq!(info.title, Some("{1-2}".to_string()));
+        assert!(info.highlighted.is_empty());
+    }
+
+    #[test]
+    fn ranges() {
+        let info = CodeInfo::parse("rust {1, 3-5,x,7-}");
+        assert_eq!(info.highlighted, vec![(1, 1), (3, 5)]);
+        assert!(info.is_highlighted(4));
+        assert!(!info.is_highlighted(2));
+        assert!(!info.is_highlighted(7));
+    }
+
+    #[test]
+    fn rustdoc_flags() {
+        let info = CodeInfo::parse("no_run,should_panic");
+        assert_eq!(info.lang, "rust");
+        assert_eq!(info.flags, vec!["no_run", "should_panic"]);
+        assert_eq!(info.caption(), Some("[no_run] [should_panic]".to_string()));
+
+        let info = CodeInfo::parse("rust,ignore");
+        assert_eq!(info.lang, "rust");
+        assert_eq!(info.flags, vec!["ignore"]);
+    }
+
+    #[test]
+    fn unknown_flags() {
+        let info = CodeInfo::parse("c frobnicate,linenums");
+        assert_eq!(info.lang, "c");
+        assert!(info.flags.is_empty());
+        assert!(info.line_numbers);
+        assert_eq!(info.caption(), None);
+    }
+
+    #[test]
+    fn empty() {
+        let info = CodeInfo::parse("");
+        assert_eq!(info.lang, "");
+        assert_eq!(info.title, None);
+        assert!(!info.line_numbers);
+    }
+}
@@ -5,7 +5,9 @@
 //! DOM for ANSI terminal rendering
 
 use std::fmt;
+use std::cmp;
 use std::borrow::Cow;
+use std::ops::Range;
 
 use ansi_term::{Style, Colour};
 use ansi_term::ANSIString;
@@ -13,15 +15,107 @@ use ansi_term::ANSIString;
 use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
-use super::OutputKind;
+use xi_unicode::LineBreakIterator;
 
-fn findsplit(s: &str, pos: usize) -> usize {
-    if let Some(n) = UnicodeSegmentation::grapheme_indices(s, true).nth(pos) {
-        return n.0;
+use super::{OutputKind, RenderError};
+
+// smallest range covering both
+fn join_spans(a: Option<Range<usize>>, b: Option<Range<usize>>) -> Option<Range<usize>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(cmp::min(a.start, b.start)..cmp::max(a.end, b.end)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// length in bytes of the escape sequence `s` starts with, if any
+fn escape_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    if bytes.len() < 2 || bytes[0] != 0x1b {
+        return 0;
+    }
+    match bytes[1] {
+        b'[' => {
+            // CSI: parameters, then a final byte
+            match bytes[2..].iter().position(|&b| b >= 0x40 && b <= 0x7e) {
+                Some(pos) => pos + 3,
+                None => bytes.len(),
+            }
+        }
+        b']' => {
+            // OSC: terminated by BEL or ST
+            let mut i = 2;
+            while i < bytes.len() {
+                if bytes[i] == 0x07 {
+                    return i + 1;
+                }
+                if bytes[i] == 0x1b && i + 1 < bytes.len() && bytes[i + 1] == b'\\' {
+                    return i + 2;
+                }
+                i += 1;
+            }
+            bytes.len()
+        }
+        // a two character escape, the second of which may be multibyte
+        _ => 1 + s[1..].chars().next().map_or(0, char::len_utf8),
+    }
+}
+
+// number of columns taken by `s` on the terminal, escape sequences
+// don't take any
+fn text_width(s: &str) -> u16 {
+    let mut width = 0;
+    let mut rest = s;
+    while let Some(pos) = rest.find('\x1b') {
+        width += UnicodeWidthStr::width(&rest[..pos]);
+        let len = cmp::max(escape_len(&rest[pos..]), 1);
+        rest = &rest[pos + len..];
+    }
+    (width + UnicodeWidthStr::width(rest)) as u16
+}
+
+// byte index of the longest prefix of `s` fitting in `width` columns, but
+// always at least one grapheme
+fn findsplit(s: &str, width: u16) -> usize {
+    let mut used = 0;
+    let mut escape_end = 0;
+    for (i, grapheme) in UnicodeSegmentation::grapheme_indices(s, true) {
+        if i < escape_end {
+            continue;
+        }
+        let len = escape_len(&s[i..]);
+        if len > 0 {
+            escape_end = i + len;
+            continue;
+        }
+        used += text_width(grapheme);
+        if used > width && i > 0 {
+            return i;
+        }
     }
     s.len()
 }
 
+// byte index of the last line break opportunity of `s` such that the text
+// before it, without its trailing spaces, fits in `width` columns. The end of
+// `s` is followed by `glue` columns which must stay on the same line
+fn find_line_break(s: &str, width: u16, glue: u16) -> usize {
+    let mut best = 0;
+    let mut prev = 0;
+    let mut used = 0;
+    for (pos, _) in LineBreakIterator::new(s) {
+        let segment = &s[prev..pos];
+        let after = if pos == s.len() { glue } else { 0 };
+        if used + text_width(segment.trim_end()) + after > width {
+            break;
+        }
+        best = pos;
+        used += text_width(segment);
+        prev = pos;
+    }
+    best
+}
+
 pub fn split_at_in_place<'a>(cow: &mut Cow<'a, str>, mid: usize) -> Cow<'a, str> {
     match *cow {
         Cow::Owned(ref mut s) => {
@@ -48,18 +142,114 @@ pub enum TermColor {
     White,
 }
 
+// the 16 colors of the xterm palette
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+// levels of each channel in the 6x6x6 color cube
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn index_to_rgb(idx: u8) -> (u8, u8, u8) {
+    if idx < 16 {
+        PALETTE[idx as usize]
+    } else if idx < 232 {
+        let idx = (idx - 16) as usize;
+        (
+            CUBE_LEVELS[idx / 36],
+            CUBE_LEVELS[idx / 6 % 6],
+            CUBE_LEVELS[idx % 6],
+        )
+    } else {
+        let level = 8 + 10 * (idx - 232);
+        (level, level, level)
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let dr = a.0 as i32 - b.0 as i32;
+    let dg = a.1 as i32 - b.1 as i32;
+    let db = a.2 as i32 - b.2 as i32;
+    (dr * dr + dg * dg + db * db) as u32
+}
+
+// index of the closest color in the first `n` colors of the palette
+fn nearest_in_palette(rgb: (u8, u8, u8), n: usize) -> u8 {
+    let mut best = 0;
+    for i in 1..n {
+        if distance(rgb, PALETTE[i]) < distance(rgb, PALETTE[best]) {
+            best = i;
+        }
+    }
+    best as u8
+}
+
+// closest color in the cube or in the grey ramp
+fn nearest_in_256(rgb: (u8, u8, u8)) -> u8 {
+    let level = |c: u8| {
+        let mut best = 0;
+        for i in 1..CUBE_LEVELS.len() {
+            if (CUBE_LEVELS[i] as i32 - c as i32).abs() <
+                (CUBE_LEVELS[best] as i32 - c as i32).abs()
+            {
+                best = i;
+            }
+        }
+        best as u8
+    };
+    let cube = 16 + level(rgb.0) * 36 + level(rgb.1) * 6 + level(rgb.2);
+    let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let grey = if average < 8 {
+        232
+    } else if average > 238 {
+        255
+    } else {
+        232 + ((average - 8 + 5) / 10) as u8
+    };
+    if distance(rgb, index_to_rgb(grey)) < distance(rgb, index_to_rgb(cube)) {
+        grey
+    } else {
+        cube
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ColorValue {
+    // index in the terminal palette
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
 #[derive(Debug, Default, Clone)]
-pub struct DomColor(Option<u8>);
+pub struct DomColor(Option<ColorValue>);
 
 impl DomColor {
     pub fn default() -> DomColor {
         DomColor(None)
     }
     pub fn from_dark(color: TermColor) -> DomColor {
-        DomColor(Some(color as u8))
+        DomColor(Some(ColorValue::Indexed(color as u8)))
     }
     pub fn from_light(color: TermColor) -> DomColor {
-        DomColor(Some(color as u8 + 8))
+        DomColor(Some(ColorValue::Indexed(color as u8 + 8)))
+    }
+    pub fn from_index(idx: u8) -> DomColor {
+        DomColor(Some(ColorValue::Indexed(idx)))
     }
     pub fn from_grey(level: u8) -> DomColor {
         let mut level = level >> 4;
@@ -68,23 +258,87 @@ impl DomColor {
             15 => 231,
             grey => 231 + grey,
         };
-        DomColor(Some(level))
+        DomColor(Some(ColorValue::Indexed(level)))
     }
     pub fn from_color(red: u8, green: u8, blue: u8) -> DomColor {
-        if (red >> 4) == (green >> 4) && (green >> 4) == (blue >> 4) {
-            return DomColor::from_grey(red);
-        }
-        let red = (red as u32 * 6 / 256) as u8;
-        let green = (green as u32 * 6 / 256) as u8;
-        let blue = (blue as u32 * 6 / 256) as u8;
-        DomColor(Some(16 + red * 36 + green * 6 + blue))
+        DomColor(Some(ColorValue::Rgb(red, green, blue)))
     }
+    // palette index, for palette colors
     pub fn index(&self) -> Option<u8> {
-        self.0
+        match self.0 {
+            Some(ColorValue::Indexed(idx)) => Some(idx),
+            _ => None,
+        }
+    }
+    pub fn rgb(&self) -> Option<(u8, u8, u8)> {
+        match self.0 {
+            None => None,
+            Some(ColorValue::Indexed(idx)) => Some(index_to_rgb(idx)),
+            Some(ColorValue::Rgb(r, g, b)) => Some((r, g, b)),
+        }
+    }
+    // palette colors are kept as such, so that they follow the terminal
+    // color scheme
+    pub fn to_true(&self) -> Option<Colour> {
+        match self.0 {
+            None => None,
+            Some(ColorValue::Indexed(idx)) => Some(Colour::Fixed(idx)),
+            Some(ColorValue::Rgb(r, g, b)) => Some(Colour::RGB(r, g, b)),
+        }
+    }
+    pub fn to_256(&self) -> Option<u8> {
+        match self.0 {
+            Some(ColorValue::Rgb(r, g, b)) => Some(nearest_in_256((r, g, b))),
+            Some(ColorValue::Indexed(idx)) => Some(idx),
+            None => None,
+        }
+    }
+    pub fn to_16(&self) -> Option<u8> {
+        match self.0 {
+            Some(ColorValue::Indexed(idx)) if idx < 16 => Some(idx),
+            _ => self.rgb().map(|rgb| nearest_in_palette(rgb, 16)),
+        }
+    }
+    pub fn to_8(&self) -> Option<u8> {
+        match self.0 {
+            Some(ColorValue::Indexed(idx)) if idx < 8 => Some(idx),
+            _ => self.rgb().map(|rgb| nearest_in_palette(rgb, 8)),
+        }
+    }
+    // whether this is one of the palette colors used to set apart some
+    // kind of content, rather than a plain text or highlighting color
+    fn is_accent(&self) -> bool {
+        match self.0 {
+            Some(ColorValue::Indexed(idx)) => {
+                let idx = idx % 8;
+                idx != 0 && idx != 7
+            }
+            _ => false,
+        }
+    }
+    fn is_black(&self) -> bool {
+        match self.0 {
+            None | Some(ColorValue::Indexed(0)) => true,
+            _ => false,
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+// one of the 8 basic colors, available on every color terminal
+fn basic_colour(idx: u8) -> Colour {
+    match idx % 8 {
+        0 => Colour::Black,
+        1 => Colour::Red,
+        2 => Colour::Green,
+        3 => Colour::Yellow,
+        4 => Colour::Blue,
+        5 => Colour::Purple,
+        6 => Colour::Cyan,
+        _ => Colour::White,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TextAlign {
     Left,
     Center,
@@ -112,6 +366,52 @@ impl Default for BorderType {
     }
 }
 
+// which horizontal rule of a grid is being drawn
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum GridLine {
+    Top,
+    Middle,
+    Bottom,
+}
+
+impl BorderType {
+    fn horizontal(&self) -> char {
+        match *self {
+            BorderType::Empty => ' ',
+            BorderType::Dash => '╌',
+            BorderType::Thin => '─',
+            BorderType::Double => '═',
+            BorderType::Bold => '━',
+        }
+    }
+
+    fn vertical(&self) -> char {
+        match *self {
+            BorderType::Empty => ' ',
+            BorderType::Dash => '╎',
+            BorderType::Thin => '│',
+            BorderType::Double => '║',
+            BorderType::Bold => '┃',
+        }
+    }
+
+    // returns the (left, junction, right) glyphs of a grid line
+    fn grid_corners(&self, line: GridLine) -> (char, char, char) {
+        match (*self, line) {
+            (BorderType::Empty, _) => (' ', ' ', ' '),
+            (BorderType::Double, GridLine::Top) => ('╔', '╦', '╗'),
+            (BorderType::Double, GridLine::Middle) => ('╠', '╬', '╣'),
+            (BorderType::Double, GridLine::Bottom) => ('╚', '╩', '╝'),
+            (BorderType::Bold, GridLine::Top) => ('┏', '┳', '┓'),
+            (BorderType::Bold, GridLine::Middle) => ('┣', '╋', '┫'),
+            (BorderType::Bold, GridLine::Bottom) => ('┗', '┻', '┛'),
+            (_, GridLine::Top) => ('┌', '┬', '┐'),
+            (_, GridLine::Middle) => ('├', '┼', '┤'),
+            (_, GridLine::Bottom) => ('└', '┴', '┘'),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct DomStyle {
     pub bg: DomColor,
@@ -128,41 +428,82 @@ pub struct DomStyle {
     pub bottom_nb_type: BorderType,
     pub left_nb_type: BorderType,
     pub right_nb_type: BorderType,
+    // target of the link the text is part of
+    pub link: Option<String>,
+    // whether the text is wrapped in an OSC 8 hyperlink to the target
+    pub hyperlink: bool,
 }
 
 impl DomStyle {
     pub fn to_ansi(&self, kind: &OutputKind) -> Style {
+        let mut astyle = Style::new();
         match *kind {
-            OutputKind::Plain => Style::new(),
+            OutputKind::Plain => return astyle,
+            OutputKind::TrueColor => {
+                if let Some(colour) = self.fg.to_true() {
+                    astyle = astyle.fg(colour);
+                }
+                if let Some(colour) = self.bg.to_true() {
+                    astyle = astyle.on(colour);
+                }
+            }
             OutputKind::Color => {
-                let mut astyle = Style::new();
-                match self.fg.index() {
-                    None => {}
-                    Some(idx) => {
-                        astyle = astyle.fg(Colour::Fixed(idx));
-                    }
+                if let Some(idx) = self.fg.to_256() {
+                    astyle = astyle.fg(Colour::Fixed(idx));
+                }
+                if let Some(idx) = self.bg.to_256() {
+                    astyle = astyle.on(Colour::Fixed(idx));
                 }
-                match self.bg.index() {
-                    None => {}
-                    Some(idx) => {
-                        astyle = astyle.on(Colour::Fixed(idx));
+            }
+            OutputKind::Color16 => {
+                // bright foregrounds are obtained with bold, backgrounds can
+                // only be dark
+                if let Some(idx) = self.fg.to_16() {
+                    astyle = astyle.fg(basic_colour(idx));
+                    if idx >= 8 {
+                        astyle = astyle.bold();
                     }
                 }
-                if self.bold {
+                if let Some(idx) = self.bg.to_8() {
+                    astyle = astyle.on(basic_colour(idx));
+                }
+            }
+            OutputKind::Color8 => {
+                if let Some(idx) = self.fg.to_8() {
+                    astyle = astyle.fg(basic_colour(idx));
+                }
+                if let Some(idx) = self.bg.to_8() {
+                    astyle = astyle.on(basic_colour(idx));
+                }
+            }
+            OutputKind::Monochrome => {
+                // colors become attributes, and only bold, underline and
+                // reverse video are used
+                if self.fg.is_accent() || self.bold {
                     astyle = astyle.bold();
                 }
-                if self.underline {
+                if self.underline || self.italic {
                     astyle = astyle.underline();
                 }
-                if self.strikethrough {
-                    astyle = astyle.strikethrough();
-                }
-                if self.italic {
-                    astyle = astyle.italic();
+                if !self.bg.is_black() {
+                    astyle = astyle.reverse();
                 }
-                astyle
+                return astyle;
             }
         }
+        if self.bold {
+            astyle = astyle.bold();
+        }
+        if self.underline {
+            astyle = astyle.underline();
+        }
+        if self.strikethrough {
+            astyle = astyle.strikethrough();
+        }
+        if self.italic {
+            astyle = astyle.italic();
+        }
+        astyle
     }
 
     #[cfg(never)]
@@ -179,7 +520,7 @@ pub enum BoxKind<'a> {
     Header(u8),
     List(Option<u16>),
     ListBullet,
-    Table,
+    Table(Vec<TextAlign>),
     TableColumn,
     TableItem,
     Image,
@@ -190,6 +531,11 @@ struct BoxCursor {
     container: BoxSize,
     x: u16,
     y: u16,
+    // where the current line starts, words are only split there
+    line_start: u16,
+    // columns of the text right after the box being laid out which can't be
+    // put on another line than its end, as there's no break between them
+    glue: u16,
 }
 
 impl fmt::Display for BoxCursor {
@@ -237,10 +583,10 @@ impl BoxSize {
 
 #[derive(Default, Debug, Copy, Clone)]
 pub struct Rect {
-    x: u16,
-    y: u16,
-    w: u16,
-    h: u16,
+    pub x: u16,
+    pub y: u16,
+    pub w: u16,
+    pub h: u16,
 }
 
 #[derive(Default, Debug, Copy, Clone)]
@@ -258,12 +604,26 @@ enum LayoutRes<T> {
     Reject,
 }
 
+// why a box was cut in two during the layout
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Split {
+    None,
+    // the line was too long
+    Wrap,
+    // there was a line break, which got removed
+    Break,
+}
+
 #[derive(Debug, Clone)]
 pub struct DomBox<'a> {
     pub kind: BoxKind<'a>,
     pub size: BoxSize,
     pub style: DomStyle,
     pub children: Vec<DomBox<'a>>, // TODO no pub => get_children()
+    // whether this box continues its previous sibling, after a layout
+    split: Split,
+    // bytes of the Markdown text the box comes from, if any
+    pub span: Option<Range<usize>>,
 }
 
 impl<'a> DomBox<'a> {
@@ -278,8 +638,13 @@ impl<'a> DomBox<'a> {
             kind: BoxKind::Block,
             style: Default::default(),
             children: vec![],
+            split: Split::None,
+            span: None,
         }
     }
+    pub fn set_width(&mut self, width: u16) {
+        self.size.content.w = width;
+    }
     pub fn swallow(&mut self, existing: DomBox<'a>) {
         self.children.push(existing);
     }
@@ -295,6 +660,8 @@ impl<'a> DomBox<'a> {
                             kind: BoxKind::InlineContainer,
                             style: self.style.clone(),
                             children: vec![],
+                            split: Split::None,
+                            span: None,
                         });
                     }
                 }
@@ -322,9 +689,27 @@ impl<'a> DomBox<'a> {
             kind: BoxKind::Text(text),
             style: inline_container.style.clone(),
             children: vec![],
+            split: Split::None,
+            span: None,
         });
         inline_container.children.last_mut().unwrap()
     }
+    // add text before the existing children of an inline container
+    pub fn prepend_text(&mut self, text: Cow<'a, str>) -> &mut DomBox<'a> {
+        let style = self.style.clone();
+        self.children.insert(
+            0,
+            DomBox {
+                size: Default::default(),
+                kind: BoxKind::Text(text),
+                style: style,
+                children: vec![],
+                split: Split::None,
+                span: None,
+            },
+        );
+        &mut self.children[0]
+    }
     pub fn add_inline(&mut self) -> &mut DomBox<'a> {
         let inline_container = self.get_inline_container();
         inline_container.children.push(DomBox {
@@ -332,6 +717,8 @@ impl<'a> DomBox<'a> {
             kind: BoxKind::Inline,
             style: inline_container.style.clone(),
             children: vec![],
+            split: Split::None,
+            span: None,
         });
         inline_container.children.last_mut().unwrap()
     }
@@ -341,6 +728,8 @@ impl<'a> DomBox<'a> {
             kind: BoxKind::Block,
             style: self.style.clone(),
             children: vec![],
+            split: Split::None,
+            span: None,
         });
         self.children.last_mut().unwrap()
     }
@@ -350,6 +739,8 @@ impl<'a> DomBox<'a> {
             kind: BoxKind::Header(level),
             style: self.style.clone(),
             children: vec![],
+            split: Split::None,
+            span: None,
         });
         self.children.last_mut().unwrap()
     }
@@ -359,6 +750,8 @@ impl<'a> DomBox<'a> {
             kind: BoxKind::List(start),
             style: self.style.clone(),
             children: vec![],
+            split: Split::None,
+            span: None,
         });
         self.children.last_mut().unwrap()
     }
@@ -368,36 +761,321 @@ impl<'a> DomBox<'a> {
             kind: BoxKind::ListBullet,
             style: self.style.clone(),
             children: vec![],
+            split: Split::None,
+            span: None,
         });
         self.children.last_mut().unwrap()
     }
+    pub fn add_table(&mut self, aligns: Vec<TextAlign>) -> &mut DomBox<'a> {
+        self.children.push(DomBox {
+            size: Default::default(),
+            kind: BoxKind::Table(aligns),
+            style: self.style.clone(),
+            children: vec![],
+            split: Split::None,
+            span: None,
+        });
+        self.children.last_mut().unwrap()
+    }
+    // tables are stored column by column, each column holding one item per row
+    pub fn add_table_item(&mut self, col: usize, row: usize) -> &mut DomBox<'a> {
+        while self.children.len() <= col {
+            let align = match self.kind {
+                BoxKind::Table(ref aligns) => {
+                    aligns.get(self.children.len()).cloned().unwrap_or_default()
+                }
+                _ => TextAlign::Left,
+            };
+            let mut column = DomBox {
+                size: Default::default(),
+                kind: BoxKind::TableColumn,
+                style: self.style.clone(),
+                children: vec![],
+                split: Split::None,
+                span: None,
+            };
+            column.style.align = align;
+            self.children.push(column);
+        }
+        let column = &mut self.children[col];
+        column.fill_table_column(row);
+        column.children.push(DomBox {
+            size: Default::default(),
+            kind: BoxKind::TableItem,
+            style: column.style.clone(),
+            children: vec![],
+            split: Split::None,
+            span: None,
+        });
+        column.children.last_mut().unwrap()
+    }
+    // make sure every column has an item for each row up to `row`
+    pub fn fill_table_row(&mut self, row: usize) {
+        for column in &mut self.children {
+            column.fill_table_column(row + 1);
+        }
+    }
+    fn fill_table_column(&mut self, rows: usize) {
+        while self.children.len() < rows {
+            self.children.push(DomBox {
+                size: Default::default(),
+                kind: BoxKind::TableItem,
+                style: self.style.clone(),
+                children: vec![],
+                split: Split::None,
+                span: None,
+            });
+        }
+    }
+    // strip the whitespace at both ends of the content, like the padding
+    // around table cells
+    pub fn trim_text(&mut self) {
+        self.trim_text_start();
+        self.trim_text_end();
+    }
+    fn trim_text_start(&mut self) -> bool {
+        if let BoxKind::Text(ref mut text) = self.kind {
+            let pos = text.len() - text.trim_start().len();
+            *text = split_at_in_place(text, pos);
+            return !text.is_empty();
+        }
+        self.children.iter_mut().any(|child| child.trim_text_start())
+    }
+    fn trim_text_end(&mut self) -> bool {
+        if let BoxKind::Text(ref mut text) = self.kind {
+            let pos = text.trim_end().len();
+            split_at_in_place(text, pos);
+            return !text.is_empty();
+        }
+        self.children.iter_mut().rev().any(|child| child.trim_text_end())
+    }
     pub fn add_break(&mut self) -> &mut DomBox<'a> {
         self.children.push(DomBox {
             size: Default::default(),
             kind: BoxKind::Break,
             style: self.style.clone(),
             children: vec![],
+            split: Split::None,
+            span: None,
         });
         self.children.last_mut().unwrap()
     }
-    pub fn layout(&mut self) {
+    // can be called again on a laid out tree, for another width
+    pub fn layout(&mut self) -> Result<(), RenderError> {
+        self.unsplit();
+        let width = self.size.content.w;
         let mut cursor = BoxCursor {
             x: 0,
             y: 0,
             container: self.size,
+            line_start: 0,
+            glue: 0,
         };
-        self.layout_generic(&mut cursor);
+        self.layout_generic(&mut cursor)?;
+        // what can't be split, like a wide character, may still not fit
+        if self.size.width_plus_border() > width {
+            return Err(RenderError::WidthOverflow {
+                needed: self.size.width_plus_border(),
+                available: width,
+            });
+        }
+        Ok(())
+    }
+    // glue back the boxes cut by a previous layout
+    fn unsplit(&mut self) {
+        let mut children: Vec<DomBox<'a>> = Vec::with_capacity(self.children.len());
+        for child in self.children.drain(..) {
+            match children.last_mut() {
+                Some(previous) if child.split != Split::None => previous.merge(child),
+                _ => children.push(child),
+            }
+        }
+        for child in &mut children {
+            child.unsplit();
+        }
+        self.children = children;
+    }
+    fn merge(&mut self, next: DomBox<'a>) {
+        if next.split == Split::Break {
+            self.add_break();
+        }
+        self.span = join_spans(self.span.take(), next.span);
+        match (&mut self.kind, next.kind) {
+            (&mut BoxKind::Text(ref mut text), BoxKind::Text(ref rest)) => {
+                text.to_mut().push_str(rest);
+            }
+            _ => self.children.extend(next.children),
+        }
+    }
+    // copy of the document text, without any styling, laid out as a simple
+    // list of paragraphs: used when the real document can't be laid out
+    pub fn flatten(&self) -> DomBox<'a> {
+        let mut unsplit = self.clone();
+        unsplit.unsplit();
+        let mut root = DomBox::new_root(self.size.content.w);
+        unsplit.flatten_into(&mut root);
+        root.fill_spans();
+        root
+    }
+    fn flatten_into(&self, root: &mut DomBox<'a>) {
+        match self.kind {
+            BoxKind::Text(ref text) => {
+                let child = root.last_block().add_text(text.clone());
+                child.span = self.span.clone();
+            }
+            BoxKind::Break => {
+                let child = root.last_block().add_break();
+                child.span = self.span.clone();
+            }
+            BoxKind::Inline | BoxKind::InlineContainer => {
+                for child in &self.children {
+                    child.flatten_into(root);
+                }
+            }
+            _ => {
+                root.add_block();
+                for child in &self.children {
+                    child.flatten_into(root);
+                }
+                root.add_block();
+            }
+        }
+    }
+    // block the flattened text goes in, there's none yet when the text
+    // isn't inside of one
+    fn last_block(&mut self) -> &mut DomBox<'a> {
+        if self.children.is_empty() {
+            self.add_block();
+        }
+        self.children.last_mut().unwrap()
+    }
+    pub fn uppercase(&mut self) {
+        if let BoxKind::Text(ref mut text) = self.kind {
+            *text = Cow::Owned(text.to_uppercase());
+        }
+        for child in &mut self.children {
+            child.uppercase();
+        }
+    }
+    // text box the inline container starts with, if it's not inside of an
+    // inline, like code or emphasis
+    pub fn leading_text(&mut self) -> Option<&mut DomBox<'a>> {
+        if let BoxKind::InlineContainer = self.kind {
+            let child = self.children.first_mut()?;
+            if let BoxKind::Text(_) = child.kind {
+                return Some(child);
+            }
+        }
+        None
+    }
+    // text of the box, without any styling
+    pub fn plain_text(&self) -> String {
+        match self.kind {
+            BoxKind::Text(ref text) => text.to_string(),
+            BoxKind::Break => " ".to_string(),
+            _ => self.children.iter().map(|child| child.plain_text()).collect(),
+        }
+    }
+    // line, level and text of the headers, once laid out
+    pub fn headers(&self, headers: &mut Vec<(u16, u8, String)>) {
+        if let BoxKind::Header(level) = self.kind {
+            let line = self.size.content.y - self.size.border.top;
+            headers.push((line, level, self.plain_text().trim().to_string()));
+            return;
+        }
+        for child in &self.children {
+            child.headers(headers);
+        }
+    }
+    // give `span` to the text boxes added at the end of the tree since the
+    // last call, and tell whether there were any
+    pub fn set_new_spans(&mut self, span: &Range<usize>) -> bool {
+        let mut found = false;
+        for child in self.children.iter_mut().rev() {
+            match child.kind {
+                BoxKind::Text(_) | BoxKind::Break => {
+                    if child.span.is_some() {
+                        break;
+                    }
+                    child.span = Some(span.clone());
+                    found = true;
+                }
+                _ => {
+                    if child.set_new_spans(span) {
+                        found = true;
+                    } else if !child.children.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+        found
+    }
+    // bytes of the Markdown shown on each line, once laid out: the ones of
+    // its text, or else of the innermost box covering it
+    pub fn line_spans(&self) -> Vec<Option<Range<usize>>> {
+        let height = self.size.height_plus_border() as usize;
+        let mut texts = vec![None; height];
+        self.text_spans(&mut texts);
+        // the whole document doesn't tell anything
+        let mut boxes = vec![None; height];
+        for child in &self.children {
+            child.box_spans(&mut boxes);
+        }
+        texts.into_iter().zip(boxes).map(|(text, b)| text.or(b)).collect()
+    }
+    fn text_spans(&self, lines: &mut Vec<Option<Range<usize>>>) {
+        match self.kind {
+            BoxKind::Text(_) => {
+                let line = self.size.content.y as usize;
+                if self.size.content.h > 0 && line < lines.len() {
+                    lines[line] = join_spans(lines[line].take(), self.span.clone());
+                }
+            }
+            _ => {
+                for child in &self.children {
+                    child.text_spans(lines);
+                }
+            }
+        }
+    }
+    fn box_spans(&self, lines: &mut Vec<Option<Range<usize>>>) {
+        if let Some(ref span) = self.span {
+            let top = (self.size.content.y - self.size.border.top) as usize;
+            let bottom = cmp::min(top + self.size.height_plus_border() as usize, lines.len());
+            for line in top..bottom {
+                lines[line] = Some(span.clone());
+            }
+        }
+        for child in &self.children {
+            child.box_spans(lines);
+        }
+    }
+    // containers without a span get the one covering their children
+    pub fn fill_spans(&mut self) {
+        let mut span = None;
+        for child in &mut self.children {
+            child.fill_spans();
+            span = join_spans(span, child.span.clone());
+        }
+        if self.span.is_none() {
+            self.span = span;
+        }
     }
     fn inline_children_loop(
         &mut self,
         res: LayoutRes<DomBox<'a>>,
         dorej: bool,
-    ) -> LayoutRes<DomBox<'a>> {
+        line_start: u16,
+        glue: u16,
+    ) -> Result<LayoutRes<DomBox<'a>>, RenderError> {
         let mut res = res;
         let mut subcursor = BoxCursor {
             x: self.size.content.x,
             y: self.size.content.y,
             container: self.size,
+            line_start: line_start,
+            glue: 0,
         };
         let mut i = 0;
         while i < self.children.len() {
@@ -408,10 +1086,13 @@ impl<'a> DomBox<'a> {
                     size: self.size.clone(),
                     style: self.style.clone(),
                     children: self.children.split_off(i),
+                    split: Split::Break,
+                    span: self.span.clone(),
                 });
                 break;
             }
-            match self.children[i].layout_generic(&mut subcursor) {
+            subcursor.glue = self.glue_after(i, glue);
+            match self.children[i].layout_generic(&mut subcursor)? {
                 LayoutRes::Normal => (),
                 LayoutRes::CutHere(next) => {
                     self.children.insert(i + 1, next);
@@ -420,6 +1101,8 @@ impl<'a> DomBox<'a> {
                         size: self.size.clone(),
                         style: self.style.clone(),
                         children: self.children.split_off(i + 1),
+                        split: Split::Wrap,
+                        span: self.span.clone(),
                     });
                     break;
                 }
@@ -428,7 +1111,9 @@ impl<'a> DomBox<'a> {
                         if dorej {
                             res = LayoutRes::Reject;
                         } else {
-                            panic!("can't reject from first {:?}", self.children[i].kind);
+                            return Err(RenderError::RejectedFirstChild(
+                                format!("{:?}", self.children[i].kind),
+                            ));
                         }
                     } else {
                         res = LayoutRes::CutHere(DomBox {
@@ -436,6 +1121,8 @@ impl<'a> DomBox<'a> {
                             size: self.size.clone(),
                             style: self.style.clone(),
                             children: self.children.split_off(i),
+                            split: Split::Wrap,
+                            span: self.span.clone(),
                         });
                     }
                     break;
@@ -444,49 +1131,88 @@ impl<'a> DomBox<'a> {
             i += 1;
         }
         self.size.content.w = subcursor.x - self.size.content.x;
-        res
+        Ok(res)
+    }
+    // columns of the text after the child `i` up to the first line break
+    // opportunity, if there's none right after it. `glue` is the same for the
+    // end of the box itself
+    fn glue_after(&self, i: usize, glue: u16) -> u16 {
+        let mut text = match self.children[i].plain_text().chars().last() {
+            Some(c) => c.to_string(),
+            None => return 0,
+        };
+        let start = text.len();
+        for child in &self.children[i + 1..] {
+            text.push_str(&child.plain_text());
+            let pos = LineBreakIterator::new(&text)
+                .map(|(pos, _)| pos)
+                .find(|&pos| pos >= start)
+                .unwrap_or(text.len());
+            // the end of the text is only a break after spaces
+            if pos < text.len() || text.ends_with(char::is_whitespace) {
+                return text_width(text[start..pos].trim_end());
+            }
+        }
+        text_width(&text[start..]) + glue
+    }
+    // width left for the content when the box and its borders take
+    // `available` columns, which must leave at least one
+    fn width_inside(&self, available: u16) -> Result<u16, RenderError> {
+        let borders = self.size.border.left + self.size.border.right;
+        if available > borders {
+            Ok(available - borders)
+        } else {
+            Err(RenderError::WidthOverflow {
+                needed: borders + 1,
+                available: available,
+            })
+        }
     }
-    fn layout_generic(&mut self, cursor: &mut BoxCursor) -> LayoutRes<DomBox<'a>> {
-        let res = match self.kind {
-            BoxKind::Block |
-            BoxKind::ListBullet |
-            BoxKind::Header(_) => self.layout_block(cursor),
+    fn layout_generic(
+        &mut self,
+        cursor: &mut BoxCursor,
+    ) -> Result<LayoutRes<DomBox<'a>>, RenderError> {
+        match self.kind {
+            BoxKind::Table(_) => self.layout_table(cursor),
             BoxKind::InlineContainer => self.layout_inline_container(cursor),
             BoxKind::List(_) => self.layout_list(cursor),
             BoxKind::Text(_) | BoxKind::Inline => self.layout_inline(cursor),
-            BoxKind::Break => panic!("shouldn't layout a break"),
-            _ => panic!("unimplemented layout for {:?}", self.kind),
-        };
-        res
+            BoxKind::Break => {
+                // breaks are consumed by their container, a stray one takes
+                // no room
+                self.size.content = Rect {
+                    x: cursor.x,
+                    y: cursor.y,
+                    w: 0,
+                    h: 0,
+                };
+                Ok(LayoutRes::Normal)
+            }
+            // anything else is laid out like a plain block
+            _ => self.layout_block(cursor),
+        }
     }
-    fn layout_block(&mut self, cursor: &mut BoxCursor) -> LayoutRes<DomBox<'a>> {
+    fn layout_block(&mut self, cursor: &mut BoxCursor) -> Result<LayoutRes<DomBox<'a>>, RenderError> {
         let res = LayoutRes::Normal;
         self.size.content.x = cursor.x + self.size.border.left;
         self.size.content.y = cursor.y + self.size.border.top;
         self.size.content.h = 0;
-        self.size.content.w = if cursor.container.content.w - cursor.x +
-            cursor.container.content.x >
-            self.size.border.left + self.size.border.right
-        {
-            cursor.container.content.w - cursor.x + cursor.container.content.x -
-                self.size.border.left - self.size.border.right
-        } else {
-            1
-        };
+        // the cursor can be past the container when it's too narrow
+        let available = (cursor.container.content.w + cursor.container.content.x)
+            .saturating_sub(cursor.x);
+        self.size.content.w = self.width_inside(available)?;
         let mut subcursor = BoxCursor {
             x: self.size.content.x,
             y: self.size.content.y,
             container: self.size,
+            line_start: self.size.content.x,
+            glue: 0,
         };
         let mut max_width = 0;
         let mut i = 0;
         while i < self.children.len() {
-            if let BoxKind::Break = self.children[i].kind {
-                self.children.remove(i);
-                continue;
-            }
-
-            self.layout_child(&mut subcursor, i);
+            // a break between blocks takes no room
+            self.layout_child(&mut subcursor, i)?;
 
             self.size.content.h += self.children[i].size.height_plus_border();
 
@@ -507,27 +1233,25 @@ impl<'a> DomBox<'a> {
             cursor.y += self.size.height_plus_border();
         }
 
-        res
+        Ok(res)
     }
 
-    fn layout_child(&mut self, cursor: &mut BoxCursor, i: usize) {
-        match self.children[i].layout_generic(cursor) {
+    fn layout_child(&mut self, cursor: &mut BoxCursor, i: usize) -> Result<(), RenderError> {
+        match self.children[i].layout_generic(cursor)? {
             LayoutRes::Normal => (),
             LayoutRes::CutHere(next) => self.children.insert(i + 1, next),
             LayoutRes::Reject => {
-                panic!("can't reject a {:?}", self.children[i].kind);
+                return Err(RenderError::RejectedFirstChild(
+                    format!("{:?}", self.children[i].kind),
+                ));
             }
         }
+        Ok(())
     }
 
-    fn layout_list(&mut self, cursor: &mut BoxCursor) -> LayoutRes<DomBox<'a>> {
+    fn layout_list(&mut self, cursor: &mut BoxCursor) -> Result<LayoutRes<DomBox<'a>>, RenderError> {
         let res = LayoutRes::Normal;
-        self.size.content.w =
-            if cursor.container.content.w > self.size.border.left + self.size.border.right {
-                cursor.container.content.w - self.size.border.left - self.size.border.right
-            } else {
-                1
-            };
+        self.size.content.w = self.width_inside(cursor.container.content.w)?;
         self.size.content.h = 0;
         self.size.content.x = cursor.x + self.size.border.left;
         self.size.content.y = cursor.y + self.size.border.top;
@@ -535,84 +1259,421 @@ impl<'a> DomBox<'a> {
             x: self.size.content.x,
             y: self.size.content.y,
             container: self.size,
+            line_start: self.size.content.x,
+            glue: 0,
         };
+        // each item goes on the right of its bullet, so that its lines are
+        // all indented alike
+        let mut bullet_height = 0;
         let mut i = 0;
         while i < self.children.len() {
-            self.layout_child(&mut subcursor, i);
-            match self.children[i].kind {
-                BoxKind::ListBullet => (),
-                BoxKind::Block => {
-                    self.size.content.h += self.children[i].size.height_plus_border();
-                }
-                _ => panic!("can't layout a {:?} in a List", self.children[i].kind),
+            self.layout_child(&mut subcursor, i)?;
+            let height = self.children[i].size.height_plus_border();
+            if let BoxKind::ListBullet = self.children[i].kind {
+                bullet_height = height;
+            } else {
+                // items, or anything else, start on a line of their own
+                self.size.content.h += cmp::max(height, bullet_height);
+                bullet_height = 0;
+                subcursor.x = self.size.content.x;
+                subcursor.y = self.size.content.y + self.size.content.h;
             }
             i += 1;
         }
+        self.size.content.h += bullet_height;
         cursor.y += self.size.height_plus_border();
-        res
+        Ok(res)
+    }
+    // the table takes its natural width, shrinking the widest columns until
+    // it fits in the container. Each cell gets one space of padding on each
+    // side, and columns are separated by a border glyph.
+    fn layout_table(&mut self, cursor: &mut BoxCursor) -> Result<LayoutRes<DomBox<'a>>, RenderError> {
+        let res = LayoutRes::Normal;
+        self.size.content.x = cursor.x + self.size.border.left;
+        self.size.content.y = cursor.y + self.size.border.top;
+        let available = (cursor.container.content.w + cursor.container.content.x)
+            .saturating_sub(cursor.x);
+        let available = self.width_inside(available)?;
+        let ncols = self.children.len();
+        let nrows = self.children
+            .iter()
+            .map(|column| column.children.len())
+            .max()
+            .unwrap_or(0);
+        if ncols == 0 || nrows == 0 {
+            self.size.content.w = 0;
+            self.size.content.h = 0;
+            cursor.x = cursor.container.content.x;
+            cursor.y += self.size.height_plus_border();
+            return Ok(res);
+        }
+        self.fill_table_row(nrows - 1);
+
+        let mut widths: Vec<u16> = self.children
+            .iter()
+            .map(|column| {
+                column
+                    .children
+                    .iter()
+                    .map(|item| item.natural_width())
+                    .max()
+                    .unwrap_or(0)
+                    .max(1)
+            })
+            .collect();
+        // a column can't be narrower than the widest grapheme of its cells
+        let min_widths: Vec<u16> = self.children
+            .iter()
+            .map(|column| {
+                column
+                    .children
+                    .iter()
+                    .map(|item| item.min_width())
+                    .max()
+                    .unwrap_or(0)
+                    .max(1)
+            })
+            .collect();
+        let chrome = 3 * ncols as u16 + 1;
+        while widths.iter().sum::<u16>() + chrome > available {
+            let widest = (0..ncols).filter(|&i| widths[i] > min_widths[i]).fold(
+                None,
+                |best: Option<usize>, i| match best {
+                    Some(best) if widths[best] >= widths[i] => Some(best),
+                    _ => Some(i),
+                },
+            );
+            match widest {
+                Some(widest) => widths[widest] -= 1,
+                None => break,
+            }
+        }
+        if widths.iter().sum::<u16>() + chrome > available {
+            return Err(RenderError::WidthOverflow {
+                needed: widths.iter().sum::<u16>() + chrome,
+                available: available,
+            });
+        }
+        let mut xs = Vec::with_capacity(ncols);
+        let mut x = self.size.content.x + 2;
+        for width in &widths {
+            xs.push(x);
+            x += width + 3;
+        }
+
+        // top border
+        let mut y = self.size.content.y + 1;
+        for row in 0..nrows {
+            let mut height = 1;
+            for col in 0..ncols {
+                let item = &mut self.children[col].children[row];
+                let mut subcursor = BoxCursor {
+                    x: xs[col],
+                    y: y,
+                    line_start: xs[col],
+                    glue: 0,
+                    container: BoxSize {
+                        content: Rect {
+                            x: xs[col],
+                            y: y,
+                            w: widths[col],
+                            h: 0,
+                        },
+                        border: Default::default(),
+                    },
+                };
+                item.layout_generic(&mut subcursor)?;
+                if item.size.height_plus_border() > height {
+                    height = item.size.height_plus_border();
+                }
+            }
+            for col in 0..ncols {
+                let item = &mut self.children[col].children[row];
+                item.size.content.h = height - item.size.border.top - item.size.border.bottom;
+                item.size.content.w = widths[col];
+                item.align_lines();
+            }
+            y += height;
+            // separator after the header, bottom border after the last row
+            if row == 0 || row == nrows - 1 {
+                y += 1;
+            }
+        }
+
+        self.size.content.w = widths.iter().sum::<u16>() + chrome;
+        self.size.content.h = y - self.size.content.y;
+        for col in 0..ncols {
+            let column = &mut self.children[col];
+            column.size.content = Rect {
+                x: xs[col],
+                y: self.size.content.y,
+                w: widths[col],
+                h: self.size.content.h,
+            };
+        }
+        cursor.x = cursor.container.content.x;
+        cursor.y += self.size.height_plus_border();
+        Ok(res)
+    }
+
+    // width taken by the content if it was laid out on an infinite line
+    fn natural_width(&self) -> u16 {
+        match self.kind {
+            BoxKind::Text(ref text) => text_width(text),
+            BoxKind::Break => 0,
+            BoxKind::Inline | BoxKind::InlineContainer => {
+                let mut max_width = 0;
+                let mut width = 0;
+                for child in &self.children {
+                    if let BoxKind::Break = child.kind {
+                        max_width = max_width.max(width);
+                        width = 0;
+                    } else {
+                        width += child.natural_width();
+                    }
+                }
+                max_width.max(width)
+            }
+            _ => {
+                self.children
+                    .iter()
+                    .map(|child| child.natural_width())
+                    .max()
+                    .unwrap_or(0) + self.size.border.left + self.size.border.right
+            }
+        }
     }
+
+    // width of the widest grapheme, which can't be split across lines
+    fn min_width(&self) -> u16 {
+        match self.kind {
+            BoxKind::Text(ref text) => {
+                UnicodeSegmentation::graphemes(&text[..], true)
+                    .map(text_width)
+                    .max()
+                    .unwrap_or(0)
+            }
+            _ => {
+                self.children
+                    .iter()
+                    .map(|child| child.min_width())
+                    .max()
+                    .unwrap_or(0) + self.size.border.left + self.size.border.right
+            }
+        }
+    }
+
+    // move the lines of an already laid out block according to its alignment
+    fn align_lines(&mut self) {
+        let right = self.size.right();
+        for child in &mut self.children {
+            let room = right.saturating_sub(child.size.right() + child.size.border.right);
+            let dx = match self.style.align {
+                TextAlign::Left => 0,
+                TextAlign::Center => room / 2,
+                TextAlign::Right => room,
+            };
+            child.shift_x(dx);
+        }
+    }
+
+    fn shift_x(&mut self, dx: u16) {
+        if dx == 0 {
+            return;
+        }
+        self.size.content.x += dx;
+        for child in &mut self.children {
+            child.shift_x(dx);
+        }
+    }
+
     // this is a line, and when split will be 2 lines
-    fn layout_inline_container(&mut self, cursor: &mut BoxCursor) -> LayoutRes<DomBox<'a>> {
+    fn layout_inline_container(
+        &mut self,
+        cursor: &mut BoxCursor,
+    ) -> Result<LayoutRes<DomBox<'a>>, RenderError> {
         let mut res = LayoutRes::Normal;
-        self.size.content.w =
-            if cursor.container.content.w > self.size.border.left + self.size.border.right {
-                cursor.container.content.w - self.size.border.left - self.size.border.right
-            } else {
-                1
-            };
+        self.size.content.w = self.width_inside(cursor.container.content.w)?;
         self.size.content.h = 1;
         self.size.content.x = cursor.x + self.size.border.left;
         self.size.content.y = cursor.y + self.size.border.top;
-        res = self.inline_children_loop(res, false);
+        let line_start = self.size.content.x;
+        res = self.inline_children_loop(res, false, line_start, 0)?;
         cursor.y += self.size.height_plus_border();
-        res
+        Ok(res)
     }
     // this one can ask to be splitted if needs be, in this case the returned
     // element must be inserted right after the current one
-    fn layout_inline(&mut self, cursor: &mut BoxCursor) -> LayoutRes<DomBox<'a>> {
+    fn layout_inline(&mut self, cursor: &mut BoxCursor) -> Result<LayoutRes<DomBox<'a>>, RenderError> {
         let mut res = LayoutRes::Normal;
         self.size.content.h = 1;
         self.size.content.x = cursor.x + self.size.border.left;
         self.size.content.y = cursor.y + self.size.border.top;
-        self.size.content.w = cursor.container.content.w - (cursor.x - cursor.container.content.x) -
-            (self.size.border.left + self.size.border.right);
+        let used = cursor.x.saturating_sub(cursor.container.content.x) + self.size.border.left +
+            self.size.border.right;
+        self.size.content.w = cursor.container.content.w.saturating_sub(used);
         match self.kind {
             BoxKind::Text(ref mut text) => {
-                let width = UnicodeWidthStr::width(&text[..]) as u16;
-                if self.size.content.w == 0 {
-                    res = LayoutRes::Reject;
-                } else if width > self.size.content.w {
-                    let pos = findsplit(text, self.size.content.w as usize);
-                    let remains = split_at_in_place(text, pos);
-                    res = LayoutRes::CutHere(DomBox {
-                        kind: BoxKind::Text(remains),
-                        size: self.size.clone(),
-                        style: self.style.clone(),
-                        children: vec![],
-                    });
-                } else {
+                let width = text_width(text);
+                if width + cursor.glue <= self.size.content.w {
                     self.size.content.w = width;
+                } else {
+                    // break after the last word fitting on the line, the
+                    // spaces before the break are kept but not displayed
+                    let mut pos = find_line_break(text, self.size.content.w, cursor.glue);
+                    if pos == 0 && cursor.x == cursor.line_start && self.size.content.w > 0 {
+                        // the first word is longer than the whole line, it's
+                        // split where the line ends
+                        pos = if width <= self.size.content.w {
+                            text.len()
+                        } else {
+                            findsplit(text, self.size.content.w)
+                        };
+                    }
+                    if pos == 0 {
+                        // it takes no room on this line
+                        res = LayoutRes::Reject;
+                        self.size.content.w = 0;
+                    } else if pos < text.len() {
+                        // the span is cut too when the text is the Markdown
+                        // one, unchanged
+                        let span = match self.span {
+                            Some(ref mut span) if span.end - span.start == text.len() => {
+                                let rest = span.start + pos..span.end;
+                                span.end = rest.start;
+                                Some(rest)
+                            }
+                            ref span => span.clone(),
+                        };
+                        let remains = split_at_in_place(text, pos);
+                        res = LayoutRes::CutHere(DomBox {
+                            kind: BoxKind::Text(remains),
+                            size: self.size.clone(),
+                            style: self.style.clone(),
+                            children: vec![],
+                            split: Split::Wrap,
+                            span: span,
+                        });
+                    }
+                    if pos > 0 {
+                        self.size.content.w = text_width(text.trim_end());
+                    }
                 }
             }
-            BoxKind::Inline => {
-                res = self.inline_children_loop(res, true);
-            }
             _ => {
-                panic!("can't layout_inline {:?}", self.kind);
+                res = self.inline_children_loop(res, true, cursor.line_start, cursor.glue)?;
             }
         };
         cursor.x += self.size.content.w;
-        res
+        Ok(res)
+    }
+
+    pub fn render(&self, kind: &OutputKind) -> Result<Vec<ANSIString<'a>>, RenderError> {
+        self.render_lines(0, self.size.height_plus_border(), kind)
     }
 
-    pub fn render(&mut self, kind: &OutputKind) -> Vec<ANSIString<'a>> {
+    // only the lines from `start` to `end`, excluded
+    pub fn render_lines(
+        &self,
+        start: u16,
+        end: u16,
+        kind: &OutputKind,
+    ) -> Result<Vec<ANSIString<'a>>, RenderError> {
         let mut strings = Vec::new();
-        for line in 0..(self.size.height_plus_border()) {
-            self.render_line(line, &mut strings, kind);
+        for line in start..cmp::min(end, self.size.height_plus_border()) {
+            self.render_line(line, &mut strings, kind)?;
             strings.push(Style::default().paint("\n"));
         }
 
-        strings
+        Ok(strings)
+    }
+
+    // the children which may have something on `line`. They are laid out
+    // from top to bottom, so these are the last ones starting before the
+    // line, as long as they start on the same line as each other (the words
+    // of a line, or a list bullet and its item).
+    fn children_on_line(&self, line: u16) -> &[DomBox<'a>] {
+        fn top(child: &DomBox) -> u16 {
+            child.size.content.y - child.size.border.top
+        }
+        // number of children starting at or before the line
+        let mut lo = 0;
+        let mut hi = self.children.len();
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if top(&self.children[mid]) <= line {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo == 0 {
+            return &[];
+        }
+        let last_top = top(&self.children[lo - 1]);
+        let mut first = lo - 1;
+        while first > 0 && top(&self.children[first - 1]) == last_top {
+            first -= 1;
+        }
+        &self.children[first..lo]
+    }
+
+    // lay out the children one after the other, handing the lines of each to
+    // `write` as soon as it's laid out. A child which can't be laid out or
+    // rendered is written as plain text, the others keep their style, unlike
+    // with `layout` where the caller flattens the whole tree.
+    pub fn render_children<F>(&mut self, kind: &OutputKind, mut write: F) -> Result<(), RenderError>
+    where
+        F: FnMut(Vec<ANSIString<'a>>) -> Result<(), RenderError>,
+    {
+        self.unsplit();
+        self.size.content.x = self.size.border.left;
+        self.size.content.y = self.size.border.top;
+        self.size.content.h = 0;
+        let mut subcursor = BoxCursor {
+            x: self.size.content.x,
+            y: self.size.content.y,
+            container: self.size,
+            line_start: self.size.content.x,
+            glue: 0,
+        };
+        let mut i = 0;
+        while i < self.children.len() {
+            let top = subcursor.y;
+            let mut strings = Vec::new();
+            let rendered = self.layout_child(&mut subcursor, i).is_ok() && {
+                self.size.content.h = subcursor.y - self.size.content.y;
+                self.render_child_lines(i, top..subcursor.y, &mut strings, kind)
+                    .is_ok()
+            };
+            if !rendered {
+                let mut flat = self.children[i].flatten();
+                flat.set_width(self.size.content.w);
+                flat.layout()?;
+                strings = flat.render(&OutputKind::Plain)?;
+                subcursor.x = self.size.content.x;
+                subcursor.y = top + flat.size.height_plus_border();
+            }
+            write(strings)?;
+            i += 1;
+        }
+        self.size.content.h = subcursor.y - self.size.content.y;
+        Ok(())
+    }
+
+    // lines of a child, as rendered with the whole box
+    fn render_child_lines(
+        &self,
+        i: usize,
+        lines: Range<u16>,
+        strings: &mut Vec<ANSIString<'a>>,
+        kind: &OutputKind,
+    ) -> Result<(), RenderError> {
+        for line in lines {
+            self.render_line_of(line, &self.children[i..i + 1], strings, kind)?;
+            strings.push(Style::default().paint("\n"));
+        }
+        Ok(())
     }
 
     fn render_line(
@@ -620,50 +1681,145 @@ impl<'a> DomBox<'a> {
         line: u16,
         strings: &mut Vec<ANSIString<'a>>,
         kind: &OutputKind,
-    ) -> (u16, u16) {
+    ) -> Result<(u16, u16), RenderError> {
+        self.render_line_of(line, self.children_on_line(line), strings, kind)
+    }
+
+    // the line, with only the given children on it
+    fn render_line_of(
+        &self,
+        line: u16,
+        children: &[DomBox<'a>],
+        strings: &mut Vec<ANSIString<'a>>,
+        kind: &OutputKind,
+    ) -> Result<(u16, u16), RenderError> {
         if line < self.size.content.y - self.size.border.top ||
             line >= self.size.bottom() + self.size.border.bottom
         {
             // out of the box, don't render anything
-            return (0, 0);
+            return Ok((0, 0));
         }
         if line < self.size.content.y || line >= self.size.bottom() {
-            return self.render_borderline(line, strings, kind);
+            return Ok(self.render_borderline(line, strings, kind));
+        }
+        if let BoxKind::Table(_) = self.kind {
+            return self.render_table_line(line, strings, kind);
         }
         self.render_borderside(true, strings, kind);
         let mut pos = self.size.content.x;
         match self.kind {
             BoxKind::Text(ref text) => {
+                let mut text = &text[..];
+                if text_width(text) > self.size.content.w {
+                    // spaces at the end of a wrapped line
+                    text = text.trim_end();
+                }
                 let s = self.style.to_ansi(kind).paint(text.to_string());
-                strings.push(s);
-                pos += UnicodeWidthStr::width(&text[..]) as u16;
-                assert!(pos <= self.size.right());
+                match self.style.link {
+                    Some(ref url) if self.style.hyperlink && *kind != OutputKind::Plain &&
+                                     !text.is_empty() => {
+                        strings.push(Style::new().paint(format!("\x1b]8;;{}\x1b\\", url)));
+                        strings.push(s);
+                        strings.push(Style::new().paint("\x1b]8;;\x1b\\"));
+                    }
+                    _ => strings.push(s),
+                }
+                pos += text_width(text);
+                if pos > self.size.right() {
+                    return Err(RenderError::WidthOverflow {
+                        needed: pos - self.size.content.x,
+                        available: self.size.content.w,
+                    });
+                }
             }
             _ => {
-                for child in &self.children {
+                for child in children {
                     let insert_point = strings.len() as u16;
-                    let (start, len) = child.render_line(line, strings, kind);
+                    let (start, len) = child.render_line(line, strings, kind)?;
                     if len == 0 {
                         continue;
                     }
-                    assert!(start >= pos);
-                    assert!(start + len <= self.size.right());
+                    if start < pos || start + len > self.size.right() {
+                        return Err(RenderError::WidthOverflow {
+                            needed: (start + len).saturating_sub(self.size.content.x),
+                            available: self.size.content.w,
+                        });
+                    }
                     if start > pos {
                         self.render_charline(' ', start - pos, Some(insert_point), strings, kind);
                     }
                     pos = start + len;
                 }
-                assert!(pos <= self.size.right());
             }
         }
         if pos < self.size.right() {
             self.render_charline(' ', self.size.right() - pos, None, strings, kind);
         }
         self.render_borderside(false, strings, kind);
-        return (
+        Ok((
             self.size.content.x - self.size.border.left,
             self.size.width_plus_border(),
-        );
+        ))
+    }
+
+    fn render_table_line(
+        &self,
+        line: u16,
+        strings: &mut Vec<ANSIString<'a>>,
+        kind: &OutputKind,
+    ) -> Result<(u16, u16), RenderError> {
+        let border = self.style.border_type;
+        let in_row = self.children[0].children.iter().any(|item| {
+            line >= item.size.content.y && line < item.size.bottom()
+        });
+        if !in_row {
+            let grid_line = if line == self.size.content.y {
+                GridLine::Top
+            } else if line + 1 == self.size.bottom() {
+                GridLine::Bottom
+            } else {
+                GridLine::Middle
+            };
+            let (left, junction, right) = border.grid_corners(grid_line);
+            let mut s = String::with_capacity((self.size.content.w * 4) as usize);
+            s.push(left);
+            for (i, column) in self.children.iter().enumerate() {
+                if i > 0 {
+                    s.push(junction);
+                }
+                for _ in 0..column.size.content.w + 2 {
+                    s.push(border.horizontal());
+                }
+            }
+            s.push(right);
+            strings.push(self.style.to_ansi(kind).paint(s));
+        } else {
+            let mut separator = String::new();
+            separator.push(border.vertical());
+            separator.push(' ');
+            for column in &self.children {
+                strings.push(self.style.to_ansi(kind).paint(separator.clone()));
+                let mut rendered = false;
+                for item in &column.children {
+                    let (_, len) = item.render_line(line, strings, kind)?;
+                    if len != 0 {
+                        rendered = true;
+                        break;
+                    }
+                }
+                if !rendered {
+                    column.render_charline(' ', column.size.content.w, None, strings, kind);
+                }
+                strings.push(self.style.to_ansi(kind).paint(" "));
+            }
+            let mut s = String::new();
+            s.push(border.vertical());
+            strings.push(self.style.to_ansi(kind).paint(s));
+        }
+        Ok((
+            self.size.content.x - self.size.border.left,
+            self.size.width_plus_border(),
+        ))
     }
 
     fn render_borderline(
@@ -766,3 +1922,156 @@ impl<'a> DomBox<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pulldown_cmark::{Parser, Options, OPTION_ENABLE_TABLES, OPTION_ENABLE_FOOTNOTES};
+
+    use super::*;
+    use super::super::{ansi_renderer, RenderOptions};
+
+    fn build<'a>(text: &'a str, width: u16) -> DomBox<'a> {
+        let mut opts = Options::empty();
+        opts.insert(OPTION_ENABLE_TABLES);
+        opts.insert(OPTION_ENABLE_FOOTNOTES);
+        let events = ansi_renderer::SourceEvents::new(text, Parser::new_ext(text, opts));
+        let options = RenderOptions::new(width, OutputKind::Plain);
+        ansi_renderer::build(events, &options, false).unwrap()
+    }
+
+    // lines of the tree laid out and rendered without styles, and without
+    // the padding
+    fn plain_lines(text: &str, width: u16) -> Vec<String> {
+        let mut root = build(text, width);
+        root.set_width(width);
+        root.layout().unwrap();
+        let strings = root.render(&OutputKind::Plain).unwrap();
+        let text: String = strings.iter().map(|s| s.to_string()).collect();
+        text.lines().map(|line| line.trim_end().to_string()).collect()
+    }
+
+    #[test]
+    fn escapes_take_no_width() {
+        assert_eq!(escape_len("\x1b[1mbold"), 4);
+        assert_eq!(escape_len("\x1b]8;;url\x07link"), 9);
+        assert_eq!(escape_len("\x1bé"), 3);
+        assert_eq!(text_width("\x1b[1mbold\x1b[0m"), 4);
+        assert_eq!(text_width("a\x1béb"), 2);
+    }
+
+    #[test]
+    fn wrapping_drops_trailing_spaces() {
+        let mut root = build("aaa bbb ccc\n", 7);
+        root.set_width(7);
+        root.layout().unwrap();
+        let strings = root.render(&OutputKind::Plain).unwrap();
+        let text: String = strings.iter().map(|s| s.to_string()).collect();
+        assert_eq!(text.lines().next(), Some("aaa bbb"));
+        assert_eq!(plain_lines("aaa bbb ccc\n", 7), &["aaa bbb", "ccc", ""]);
+    }
+
+    #[test]
+    fn long_words_are_split() {
+        assert_eq!(plain_lines("a abcdefghij\n", 4), &["a", "abcd", "efgh", "ij", ""]);
+    }
+
+    #[test]
+    fn wide_characters_are_wrapped_by_width() {
+        let lines = plain_lines("日本語の文章です\n", 5);
+        assert_eq!(&lines[..4], &["日本", "語の", "文章", "です"]);
+    }
+
+    #[test]
+    fn styled_words_are_not_broken() {
+        let lines = plain_lines("aaaa foo**bar** baz and `x`.\n", 8);
+        assert_eq!(&lines[..4], &["aaaa", "foobar", "baz and", "x."]);
+    }
+
+    #[test]
+    fn table_alignments() {
+        let text = "| a | b | c |\n|:--|:-:|--:|\n| x | y | z |\n| long | long | long |\n";
+        let lines = plain_lines(text, 40);
+        assert_eq!(
+            lines[..6].to_vec(),
+            vec![
+                "┌──────┬──────┬──────┐",
+                "│ a    │  b   │    c │",
+                "├──────┼──────┼──────┤",
+                "│ x    │  y   │    z │",
+                "│ long │ long │ long │",
+                "└──────┴──────┴──────┘",
+            ]
+        );
+    }
+
+    #[test]
+    fn table_header_separator() {
+        let lines = plain_lines("| a | b |\n|---|---|\n| c | d |\n| e | f |\n", 40);
+        let separators: Vec<usize> = lines
+            .iter()
+            .enumerate()
+            .filter(|&(_, line)| line.starts_with('├'))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(separators, vec![2]);
+    }
+
+    #[test]
+    fn narrow_tables_shrink() {
+        let text = "| name | description |\n|---|---|\n| apple | a red fruit growing on trees |\n";
+        let lines = plain_lines(text, 24);
+        for line in &lines {
+            assert!(text_width(line) <= 24, "{:?}", line);
+        }
+        assert_eq!(lines[3], "│ apple │ a red fruit  │");
+        assert_eq!(lines[4], "│       │ growing on   │");
+    }
+
+    #[test]
+    fn wide_characters_in_tables() {
+        let text = "| 名前 | 値 |\n|:--|--:|\n| 日本語のテキスト | 🎉🎉 |\n";
+        for width in 1..30 {
+            let mut root = build(text, width);
+            root.set_width(width);
+            if root.layout().is_ok() {
+                let strings = root.render(&OutputKind::Plain).unwrap();
+                let text: String = strings.iter().map(|s| s.to_string()).collect();
+                for line in text.lines() {
+                    assert!(text_width(line) <= width, "{:?}", line);
+                }
+            }
+        }
+        assert_eq!(plain_lines(text, 30)[3], "│ 日本語のテキスト │ 🎉🎉 │");
+    }
+
+    #[test]
+    fn layout_wider_than_the_output_fails() {
+        let mut root = build("🎉", 1);
+        root.set_width(1);
+        match root.layout() {
+            Err(RenderError::WidthOverflow { needed: 2, available: 1 }) => (),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn inline_children_fall_back_to_plain_text() {
+        let mut root = build("<b>hi</b>\n", 10);
+        // borders leaving no room for the text
+        root.children[0].size.border.left = 10;
+        let mut out = Vec::new();
+        ansi_renderer::render_to(&mut root, 10, &OutputKind::Plain, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().trim_end(), "<b>hi</b>");
+    }
+
+    #[test]
+    fn narrow_layout_does_not_panic() {
+        let list: String = (1..11).map(|i| format!("{}. item\n", i)).collect();
+        for text in &[include_str!("../README.md"), &list[..]] {
+            for width in 1..5 {
+                // failing is fine, the caller falls back to the flat text
+                let _ = build(text, width).layout();
+            }
+        }
+    }
+}
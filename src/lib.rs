@@ -11,22 +11,806 @@ extern crate term_size;
 extern crate unicode_segmentation;
 extern crate unicode_width;
 extern crate html2runes;
+extern crate xi_unicode;
 
 mod ansi_renderer;
+mod code_info;
 mod dombox;
+mod view;
+
+use std::env;
+use std::error::Error;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use pulldown_cmark::Parser;
 use pulldown_cmark::{Options, OPTION_ENABLE_TABLES, OPTION_ENABLE_FOOTNOTES};
 
+pub use view::{Color, Edges, LayoutBox, LayoutKind, LayoutStyle, Rect};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum OutputKind {
+    /// 24-bit colors.
+    TrueColor,
+    /// 256 colors.
     Color,
+    /// The 16 basic colors, bright ones being obtained with bold.
+    Color16,
+    /// The 8 basic colors.
+    Color8,
+    /// No colors, only bold, underline and reverse video.
+    Monochrome,
     Plain,
 }
 
-pub fn render_ansi(text: &str, width: u16, kind: OutputKind) -> String {
-    let mut opts = Options::empty();
-    opts.insert(OPTION_ENABLE_TABLES);
-    opts.insert(OPTION_ENABLE_FOOTNOTES);
-    let p = Parser::new_ext(&text, opts);
-    ansi_renderer::push_ansi(p, width, kind)
+impl OutputKind {
+    /// Colors supported by the terminal, according to `COLORTERM` and `TERM`.
+    pub fn detect() -> OutputKind {
+        match env::var("COLORTERM") {
+            Ok(ref value) if value == "truecolor" || value == "24bit" => {
+                return OutputKind::TrueColor
+            }
+            _ => {}
+        }
+        match env::var("TERM") {
+            Ok(ref term) if term == "dumb" => OutputKind::Plain,
+            Ok(ref term) if term.starts_with("vt1") || term.starts_with("vt2") => {
+                OutputKind::Monochrome
+            }
+            Ok(ref term) if term == "linux" || term == "ansi" => OutputKind::Color16,
+            _ => OutputKind::Color,
+        }
+    }
+}
+
+impl FromStr for OutputKind {
+    type Err = String;
+
+    /// Parses the names used on the command line: `truecolor`, `256`, `16`,
+    /// `8`, `mono` and `plain`.
+    fn from_str(s: &str) -> Result<OutputKind, String> {
+        match s {
+            "truecolor" | "24bit" => Ok(OutputKind::TrueColor),
+            "256" => Ok(OutputKind::Color),
+            "16" => Ok(OutputKind::Color16),
+            "8" => Ok(OutputKind::Color8),
+            "mono" | "monochrome" => Ok(OutputKind::Monochrome),
+            "plain" => Ok(OutputKind::Plain),
+            _ => Err(format!("unknown color profile '{}'", s)),
+        }
+    }
+}
+
+/// When to make links clickable with OSC 8 escape sequences.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HyperlinkMode {
+    Always,
+    Never,
+    /// Only in terminals known to support them.
+    Auto,
+}
+
+impl HyperlinkMode {
+    /// Whether links should be made clickable on this terminal.
+    pub fn enabled(&self) -> bool {
+        match *self {
+            HyperlinkMode::Always => true,
+            HyperlinkMode::Never => false,
+            HyperlinkMode::Auto => hyperlinks_supported(),
+        }
+    }
+}
+
+impl FromStr for HyperlinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HyperlinkMode, String> {
+        match s {
+            "always" => Ok(HyperlinkMode::Always),
+            "never" => Ok(HyperlinkMode::Never),
+            "auto" => Ok(HyperlinkMode::Auto),
+            _ => Err(format!("unknown hyperlink mode '{}'", s)),
+        }
+    }
+}
+
+// guess from the environment whether the terminal understands OSC 8
+fn hyperlinks_supported() -> bool {
+    if env::var_os("KITTY_WINDOW_ID").is_some() || env::var_os("WT_SESSION").is_some() {
+        return true;
+    }
+    match env::var("TERM_PROGRAM") {
+        Ok(ref program) if program == "iTerm.app" || program == "WezTerm" ||
+                               program == "vscode" => return true,
+        _ => {}
+    }
+    match env::var("VTE_VERSION") {
+        // GNOME Terminal and other VTE based terminals, since 0.50
+        Ok(version) => version.parse::<u32>().map(|v| v >= 5000).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Where the numbered list of link destinations goes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReferencePlacement {
+    /// A single list at the end of the document.
+    EndOfDocument,
+    /// A list at the end of each section, before the next header of any level.
+    EachSection,
+}
+
+impl FromStr for ReferencePlacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReferencePlacement, String> {
+        match s {
+            "end" => Ok(ReferencePlacement::EndOfDocument),
+            "section" => Ok(ReferencePlacement::EachSection),
+            _ => Err(format!("unknown reference placement '{}'", s)),
+        }
+    }
+}
+
+/// How the items of an ordered list are numbered.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Numbering {
+    /// 1, 2, 3...
+    Decimal,
+    /// a, b, c... z, aa, ab...
+    LowerAlpha,
+    /// A, B, C...
+    UpperAlpha,
+    /// i, ii, iii...
+    LowerRoman,
+    /// I, II, III...
+    UpperRoman,
+}
+
+impl Numbering {
+    /// How the item `n` is numbered, in decimal when it can't be otherwise.
+    pub fn format(&self, n: usize) -> String {
+        match *self {
+            Numbering::Decimal => n.to_string(),
+            Numbering::LowerAlpha => alpha(n).unwrap_or_else(|| n.to_string()),
+            Numbering::UpperAlpha => {
+                alpha(n).map(|s| s.to_uppercase()).unwrap_or_else(
+                    || n.to_string(),
+                )
+            }
+            Numbering::LowerRoman => roman(n).unwrap_or_else(|| n.to_string()),
+            Numbering::UpperRoman => {
+                roman(n).map(|s| s.to_uppercase()).unwrap_or_else(
+                    || n.to_string(),
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for Numbering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Numbering, String> {
+        match s {
+            "1" => Ok(Numbering::Decimal),
+            "a" => Ok(Numbering::LowerAlpha),
+            "A" => Ok(Numbering::UpperAlpha),
+            "i" => Ok(Numbering::LowerRoman),
+            "I" => Ok(Numbering::UpperRoman),
+            _ => Err(format!("unknown numbering '{}'", s)),
+        }
+    }
+}
+
+// a to z, then aa, ab...
+fn alpha(mut n: usize) -> Option<String> {
+    if n == 0 {
+        return None;
+    }
+    let mut letters = vec![];
+    while n > 0 {
+        n -= 1;
+        letters.push((b'a' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    Some(letters.into_iter().rev().collect())
+}
+
+fn roman(mut n: usize) -> Option<String> {
+    const NUMERALS: [(usize, &'static str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    if n == 0 || n >= 4000 {
+        return None;
+    }
+    let mut s = String::new();
+    for &(value, numeral) in NUMERALS.iter() {
+        while n >= value {
+            s.push_str(numeral);
+            n -= value;
+        }
+    }
+    Some(s)
+}
+
+/// Lines drawn around or under a header.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BorderStyle {
+    Thin,
+    Bold,
+    Double,
+    Dashed,
+}
+
+/// What a header is framed with.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HeaderFrame {
+    /// Nothing but a blank line under it.
+    None,
+    /// A line under it.
+    Rule(BorderStyle),
+    /// A box around it.
+    Box(BorderStyle),
+}
+
+/// How the headers of a level look.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderStyle {
+    pub color: Color,
+    pub bold: bool,
+    pub underline: bool,
+    pub frame: HeaderFrame,
+    /// Whether the text is turned to upper case.
+    pub uppercase: bool,
+}
+
+impl HeaderStyle {
+    /// How the headers of `level` look by default.
+    pub fn for_level(level: u8) -> HeaderStyle {
+        let (color, bold, underline, frame, uppercase) = match level {
+            1 => (13, true, false, HeaderFrame::Box(BorderStyle::Thin), false),
+            2 => (5, true, false, HeaderFrame::Rule(BorderStyle::Bold), false),
+            3 => (4, true, false, HeaderFrame::Rule(BorderStyle::Thin), false),
+            4 => (6, true, false, HeaderFrame::Rule(BorderStyle::Dashed), false),
+            5 => (6, false, true, HeaderFrame::None, false),
+            _ => (2, false, false, HeaderFrame::None, true),
+        };
+        HeaderStyle {
+            color: Color::Indexed(color),
+            bold: bold,
+            underline: underline,
+            frame: frame,
+            uppercase: uppercase,
+        }
+    }
+}
+
+/// Syntax highlighting theme used when none is asked for.
+pub const DEFAULT_THEME: &'static str = "base16-eighties.dark";
+
+/// Settings for rendering a document.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Width of the terminal, in columns.
+    pub width: u16,
+    pub kind: OutputKind,
+    /// Name of the syntax highlighting theme used for code blocks.
+    pub theme: String,
+    /// Directory holding extra `.tmTheme` files, available by their file name
+    /// along with the bundled themes.
+    pub theme_dir: Option<PathBuf>,
+    /// Directory holding extra `.sublime-syntax` files for code blocks.
+    pub syntax_dir: Option<PathBuf>,
+    /// File of precompiled syntax definitions, as written by syntect's
+    /// `dump_to_file`.
+    pub syntax_dump: Option<PathBuf>,
+    /// Language of the code blocks whose language can't be found out,
+    /// instead of plain text.
+    pub default_language: Option<String>,
+    /// Number the lines of every code block, not only the ones asking for it
+    /// in their info string.
+    pub line_numbers: bool,
+    /// Whether link texts are made clickable.
+    pub hyperlinks: HyperlinkMode,
+    pub references: ReferencePlacement,
+    /// Numbering of ordered lists, by how deep they are among ordered lists.
+    /// Deeper lists start the sequence again.
+    pub numbering: Vec<Numbering>,
+    /// Looks of the headers, from level 1. The levels beyond the last one
+    /// look like it.
+    pub headers: Vec<HeaderStyle>,
+    /// Number the headers like 1, 1.1, 1.1.2...
+    pub number_headers: bool,
+    /// Show a table of contents where the document has a `[TOC]` line, or
+    /// else at its top.
+    pub toc: bool,
+    /// Number the entries of the table of contents like the headers.
+    pub toc_numbers: bool,
+}
+
+impl RenderOptions {
+    pub fn new(width: u16, kind: OutputKind) -> RenderOptions {
+        RenderOptions {
+            width: width,
+            kind: kind,
+            theme: DEFAULT_THEME.to_string(),
+            theme_dir: None,
+            syntax_dir: None,
+            syntax_dump: None,
+            default_language: None,
+            line_numbers: false,
+            hyperlinks: HyperlinkMode::Auto,
+            references: ReferencePlacement::EndOfDocument,
+            numbering: vec![Numbering::Decimal],
+            headers: (1..7).map(HeaderStyle::for_level).collect(),
+            number_headers: false,
+            toc: false,
+            toc_numbers: false,
+        }
+    }
+}
+
+/// A header of a rendered document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heading {
+    /// Line of the output where the header starts, from 0.
+    pub line: usize,
+    /// From 1 for `#` to 6 for `######`.
+    pub level: u8,
+    pub title: String,
+}
+
+/// A document rendered for the terminal.
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    /// ANSI text, each line ending with a newline.
+    pub text: String,
+    /// Headers of the document, in order.
+    pub headings: Vec<Heading>,
+}
+
+/// Reasons why a document couldn't be rendered.
+#[derive(Debug, Clone)]
+pub enum RenderError {
+    /// The first box of a line didn't fit in it, so the line can't be split.
+    RejectedFirstChild(String),
+    /// Some content needed more columns than were available.
+    WidthOverflow { needed: u16, available: u16 },
+    /// No syntax highlighting theme has this name.
+    UnknownTheme(String),
+    /// The extra themes couldn't be loaded.
+    ThemeLoading(String),
+    /// The extra syntax definitions couldn't be loaded.
+    SyntaxLoading(String),
+    /// No syntax definition matches this language.
+    UnknownLanguage(String),
+    /// The output couldn't be written.
+    Output(String),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenderError::RejectedFirstChild(ref kind) => {
+                write!(f, "first {} of a line doesn't fit in it", kind)
+            }
+            RenderError::WidthOverflow { needed, available } => {
+                write!(
+                    f,
+                    "content needs {} columns but only {} are available",
+                    needed,
+                    available
+                )
+            }
+            RenderError::UnknownTheme(ref name) => write!(f, "unknown theme '{}'", name),
+            RenderError::ThemeLoading(ref reason) => {
+                write!(f, "unable to load themes: {}", reason)
+            }
+            RenderError::SyntaxLoading(ref reason) => {
+                write!(f, "unable to load syntax definitions: {}", reason)
+            }
+            RenderError::UnknownLanguage(ref name) => write!(f, "unknown language '{}'", name),
+            RenderError::Output(ref reason) => write!(f, "unable to write output: {}", reason),
+        }
+    }
+}
+
+impl Error for RenderError {
+    fn description(&self) -> &str {
+        match *self {
+            RenderError::RejectedFirstChild(_) => "first box of a line rejected",
+            RenderError::WidthOverflow { .. } => "content wider than the output",
+            RenderError::UnknownTheme(_) => "unknown theme",
+            RenderError::ThemeLoading(_) => "unable to load themes",
+            RenderError::SyntaxLoading(_) => "unable to load syntax definitions",
+            RenderError::UnknownLanguage(_) => "unknown language",
+            RenderError::Output(_) => "unable to write output",
+        }
+    }
+}
+
+/// Renders `text` for a terminal `width` columns wide.
+///
+/// If the document can't be laid out as is, it is rendered again as plain
+/// unstyled paragraphs; an error is only returned if that fails too.
+pub fn render_ansi(text: &str, width: u16, kind: OutputKind) -> Result<String, RenderError> {
+    render_ansi_with(text, &RenderOptions::new(width, kind))
+}
+
+/// Same as `render_ansi`, with all the settings in `options`.
+pub fn render_ansi_with(text: &str, options: &RenderOptions) -> Result<String, RenderError> {
+    render(text, options).map(|rendered| rendered.text)
+}
+
+/// Renders `text` like `render_ansi_with`, also telling where its headers are.
+pub fn render(text: &str, options: &RenderOptions) -> Result<Rendered, RenderError> {
+    Document::new(text, options)?.render(options.width)
+}
+
+/// A parsed document, which can be rendered several times at different
+/// widths without parsing it again.
+#[derive(Debug, Clone)]
+pub struct Document<'a> {
+    text: &'a str,
+    root: dombox::DomBox<'a>,
+    kind: OutputKind,
+    // plain copy of the document, laid out when the document itself can't be
+    flat: Option<dombox::DomBox<'a>>,
+    // bytes of the text shown on each line of the layout
+    lines: Vec<Option<Range<usize>>>,
+}
+
+impl<'a> Document<'a> {
+    /// Parses `text` to be rendered with `options`, except for their width.
+    pub fn new(text: &'a str, options: &RenderOptions) -> Result<Document<'a>, RenderError> {
+        Document::build(text, options, false)
+    }
+
+    /// Parses `text` like `new`, keeping only its table of contents.
+    pub fn toc(text: &'a str, options: &RenderOptions) -> Result<Document<'a>, RenderError> {
+        Document::build(text, options, true)
+    }
+
+    fn build(
+        text: &'a str,
+        options: &RenderOptions,
+        toc_only: bool,
+    ) -> Result<Document<'a>, RenderError> {
+        let mut opts = Options::empty();
+        opts.insert(OPTION_ENABLE_TABLES);
+        opts.insert(OPTION_ENABLE_FOOTNOTES);
+        let p = Parser::new_ext(text, opts);
+        let events = ansi_renderer::SourceEvents::new(text, p);
+        Ok(Document {
+            text: text,
+            root: ansi_renderer::build(events, options, toc_only)?,
+            kind: options.kind,
+            flat: None,
+            lines: vec![],
+        })
+    }
+
+    /// Lays the document out for `width` columns, and returns its height in
+    /// lines.
+    pub fn layout(&mut self, width: u16) -> Result<usize, RenderError> {
+        self.flat = ansi_renderer::layout(&mut self.root, width)?;
+        self.lines = self.laid_out().0.line_spans();
+        Ok(self.lines.len())
+    }
+
+    /// Lays the document out for `width` columns and renders it.
+    pub fn render(&mut self, width: u16) -> Result<Rendered, RenderError> {
+        self.layout(width)?;
+        let (root, kind) = self.laid_out();
+        ansi_renderer::render(root, kind)
+    }
+
+    /// Renders the given lines of the document, as laid out by the last call
+    /// to `layout`. Parts of the document outside of them aren't looked at,
+    /// so showing a screenful of a long document is cheap.
+    pub fn render_lines(&self, lines: Range<usize>) -> Result<String, RenderError> {
+        let clamp = |line: usize| cmp::min(line, u16::MAX as usize) as u16;
+        let (root, kind) = self.laid_out();
+        ansi_renderer::render_lines(root, clamp(lines.start), clamp(lines.end), kind)
+    }
+
+    /// The boxes of the document, as laid out by the last call to `layout`.
+    pub fn tree<'d>(&'d self) -> LayoutBox<'d, 'a> {
+        view::tree(self.laid_out().0)
+    }
+
+    /// Bytes of the Markdown text shown on each line of the document, as laid
+    /// out by the last call to `layout`. Lines showing nothing from it, like
+    /// the ones of the references, have none.
+    pub fn source_ranges(&self) -> &[Option<Range<usize>>] {
+        &self.lines
+    }
+
+    /// Line of the Markdown text, counted from 0, that the given line of the
+    /// document comes from.
+    pub fn source_line(&self, line: usize) -> Option<usize> {
+        match self.lines.get(line) {
+            Some(&Some(ref range)) => Some(self.text[..range.start].matches('\n').count()),
+            _ => None,
+        }
+    }
+
+    /// First line of the document showing the byte at `offset` in the
+    /// Markdown text, or something after it.
+    pub fn line_at_offset(&self, offset: usize) -> Option<usize> {
+        self.lines.iter().position(|range| match *range {
+            Some(ref range) => range.end > offset,
+            None => false,
+        })
+    }
+
+    /// First line of the document showing the given line of the Markdown
+    /// text, counted from 0.
+    pub fn line_at_source_line(&self, source_line: usize) -> Option<usize> {
+        let offset = if source_line == 0 {
+            0
+        } else {
+            match self.text.match_indices('\n').nth(source_line - 1) {
+                Some((pos, _)) => pos + 1,
+                None => return None,
+            }
+        };
+        self.line_at_offset(offset)
+    }
+
+    /// Same as `render`, writing each top-level block to `out` as soon as it
+    /// is laid out. Only the blocks which can't be laid out are rendered as
+    /// plain text, rather than the whole document.
+    pub fn render_to<W: io::Write>(&mut self, width: u16, out: &mut W) -> Result<(), RenderError> {
+        self.flat = None;
+        self.lines.clear();
+        ansi_renderer::render_to(&mut self.root, width, &self.kind, out)
+    }
+
+    fn laid_out(&self) -> (&dombox::DomBox<'a>, &OutputKind) {
+        match self.flat {
+            Some(ref flat) => (flat, &OutputKind::Plain),
+            None => (&self.root, &self.kind),
+        }
+    }
+}
+
+/// Renders `text` like `render_ansi_with`, writing it to `out` block by block
+/// instead of keeping the whole output in memory.
+///
+/// The fallback to plain text is also made block by block, so the output can
+/// differ from the one of `render_ansi_with` when some block doesn't fit.
+pub fn render_to<W: io::Write>(
+    text: &str,
+    options: &RenderOptions,
+    out: &mut W,
+) -> Result<(), RenderError> {
+    Document::new(text, options)?.render_to(options.width, out)
+}
+
+/// Renders only the table of contents of `text`.
+pub fn render_toc(text: &str, options: &RenderOptions) -> Result<String, RenderError> {
+    Document::toc(text, options)?.render(options.width).map(
+        |rendered| rendered.text,
+    )
+}
+
+/// Names of the syntax highlighting themes usable with `options`, sorted.
+pub fn list_themes(options: &RenderOptions) -> Result<Vec<String>, RenderError> {
+    let themes = ansi_renderer::load_themes(options)?;
+    Ok(themes.themes.keys().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &'static str = "# Title
+
+Some text with a [link](http://example.com \"Example\") and a note[^a].
+
+| Name | Value |
+|:-----|------:|
+| one  | 1     |
+
+- item
+  - nested
+- [x] done
+
+> quoted
+> > twice
+
+```rust
+fn main() {}
+```
+
+[^a]: The note.
+";
+
+    // lines of the plain rendering, without the padding
+    fn plain_lines(text: &str, width: u16) -> Vec<String> {
+        let options = RenderOptions::new(width, OutputKind::Plain);
+        let rendered = render(text, &options).unwrap();
+        rendered.text.lines().map(|line| line.trim_end().to_string()).collect()
+    }
+
+    fn streamed(text: &str, options: &RenderOptions) -> String {
+        let mut out = vec![];
+        render_to(text, options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn narrow_lists_fall_back_to_plain_text() {
+        let list: String = (1..11).map(|i| format!("{}. item {}\n", i, i)).collect();
+        for width in 1..5 {
+            let options = RenderOptions::new(width, OutputKind::Color);
+            let rendered = render(&list, &options).unwrap();
+            let text: String = rendered.text.split_whitespace().collect();
+            assert!(text.ends_with("10.item10"), "{:?}", text);
+            for line in rendered.text.lines() {
+                assert!(line.chars().count() <= width as usize, "{:?}", line);
+            }
+            let mut document = Document::new(&list, &options).unwrap();
+            assert_eq!(document.render(width).unwrap().text, rendered.text);
+        }
+    }
+
+    #[test]
+    fn streaming_falls_back_block_by_block() {
+        let list: String = (1..11).map(|i| format!("{}. item {}\n", i, i)).collect();
+        let text = format!("# Hi\n\n{}", list);
+        let options = RenderOptions::new(4, OutputKind::Plain);
+        // the whole document is made plain, or only the list
+        let rendered = render(&text, &options).unwrap().text;
+        let rendered: Vec<&str> = rendered.lines().map(|line| line.trim_end()).collect();
+        let streamed = streamed(&text, &options);
+        let streamed: Vec<&str> = streamed.lines().map(|line| line.trim_end()).collect();
+        assert_eq!(&rendered[..2], &["Hi", " 1."]);
+        assert_eq!(&streamed[..4], &["┌──┐", "│Hi│", "└──┘", " 1."]);
+        assert!(rendered.ends_with(&streamed[3..]));
+    }
+
+    #[test]
+    fn bullets_by_depth() {
+        let lines = plain_lines("- one\n  - two\n    - three\n      - four\n", 24);
+        assert_eq!(&lines[..4], &["• one", "  ◦ two", "    ▪ three", "      • four"]);
+    }
+
+    #[test]
+    fn hanging_indent() {
+        let lines = plain_lines("- a long item that wraps onto a second line\n", 24);
+        assert_eq!(&lines[..2], &["• a long item that wraps", "  onto a second line"]);
+    }
+
+    #[test]
+    fn task_checkboxes() {
+        let lines = plain_lines("- [ ] todo\n- [x] done\n- [X]\n- [ ]\n- [y] not one\n", 24);
+        assert_eq!(&lines[..5], &["☐ todo", "☑ done", "☑", "☐", "• [y] not one"]);
+    }
+
+    #[test]
+    fn only_plain_text_is_a_task_marker() {
+        let text = "- `[ ]` code\n- *[x]* emphasis\n- \\[ ] escaped\n- > [ ] quoted\n";
+        let lines = plain_lines(text, 24);
+        assert_eq!(
+            &lines[..4],
+            &["• [ ] code", "• [x] emphasis", "• [ ] escaped", "• │[ ] quoted"]
+        );
+    }
+
+    #[test]
+    fn tight_and_loose_lists() {
+        let tight = plain_lines("- one\n- two\n  ```\n  code\n  ```\n- three\n\nafter\n", 24);
+        assert_eq!(&tight[..2], &["• one", "• two"]);
+        assert_eq!(&tight[4..7], &["• three", "", "after"]);
+
+        let loose = plain_lines("- one\n\n- two\n\nafter\n", 24);
+        assert_eq!(&loose[..5], &["• one", "", "• two", "", "after"]);
+    }
+
+    #[test]
+    fn alpha_numbering() {
+        let lower = Numbering::LowerAlpha;
+        assert_eq!(lower.format(1), "a");
+        assert_eq!(lower.format(26), "z");
+        assert_eq!(lower.format(27), "aa");
+        assert_eq!(lower.format(52), "az");
+        assert_eq!(lower.format(53), "ba");
+        assert_eq!(lower.format(702), "zz");
+        assert_eq!(lower.format(703), "aaa");
+        assert_eq!(lower.format(0), "0");
+        assert_eq!(Numbering::UpperAlpha.format(28), "AB");
+    }
+
+    #[test]
+    fn roman_numbering() {
+        let lower = Numbering::LowerRoman;
+        assert_eq!(lower.format(1), "i");
+        assert_eq!(lower.format(4), "iv");
+        assert_eq!(lower.format(9), "ix");
+        assert_eq!(lower.format(14), "xiv");
+        assert_eq!(lower.format(1994), "mcmxciv");
+        assert_eq!(lower.format(3999), "mmmcmxcix");
+        assert_eq!(lower.format(4000), "4000");
+        assert_eq!(lower.format(0), "0");
+        assert_eq!(Numbering::UpperRoman.format(40), "XL");
+    }
+
+    #[test]
+    fn numbering_from_str() {
+        assert_eq!("1".parse(), Ok(Numbering::Decimal));
+        assert_eq!("a".parse(), Ok(Numbering::LowerAlpha));
+        assert_eq!("I".parse(), Ok(Numbering::UpperRoman));
+        assert!("x".parse::<Numbering>().is_err());
+    }
+
+    #[test]
+    fn ordered_markers_are_right_aligned() {
+        let lines = plain_lines("8. a\n9. b\n10. c\n", 24);
+        assert_eq!(&lines[..3], &[" 8. a", " 9. b", "10. c"]);
+
+        // checkboxes take the place of the numbers, aligned with them
+        let lines = plain_lines("9. [ ] a\n10. b\n", 24);
+        assert_eq!(&lines[..2], &["  ☐ a", "10. b"]);
+    }
+
+    #[test]
+    fn numbering_by_depth() {
+        let mut options = RenderOptions::new(24, OutputKind::Plain);
+        options.numbering = vec![Numbering::Decimal, Numbering::LowerAlpha, Numbering::UpperRoman];
+        let text = "1. a\n   1. b\n      4. c\n         1. d\n";
+        let rendered = render(text, &options).unwrap();
+        let lines: Vec<&str> = rendered.text.lines().map(|line| line.trim_end()).collect();
+        assert_eq!(&lines[..4], &["1. a", "   a. b", "      IV. c", "          1. d"]);
+    }
+
+    #[test]
+    fn toc_only_with_a_toc_line() {
+        let options = RenderOptions::new(30, OutputKind::Plain);
+        let toc = render_toc("Intro\n\n[TOC]\n\n# One\n\n## Two\n", &options).unwrap();
+        let lines: Vec<&str> = toc.lines().map(|line| line.trim_end()).collect();
+        assert_eq!(&lines[..3], &["Contents", "• One", "  ◦ Two"]);
+    }
+
+    #[test]
+    fn hyperlinks_only_to_absolute_urls() {
+        let mut options = RenderOptions::new(80, OutputKind::Color);
+        options.hyperlinks = HyperlinkMode::Always;
+        let text = "[a](http://example.com), [b](other.md) and a note[^a].\n\n[^a]: The note.\n";
+        let rendered = render(text, &options).unwrap().text;
+        assert_eq!(rendered.matches("\x1b]8;;http://example.com\x1b\\").count(), 2);
+        assert_eq!(rendered.matches("\x1b]8;;\x1b\\").count(), 2);
+    }
+
+    #[test]
+    fn render_to_matches_render() {
+        for text in &[SAMPLE, include_str!("../README.md")] {
+            for kind in &[OutputKind::Plain, OutputKind::Color] {
+                for width in &[30, 50, 80] {
+                    let mut options = RenderOptions::new(*width, *kind);
+                    options.hyperlinks = HyperlinkMode::Always;
+                    let rendered = render(text, &options).unwrap();
+                    assert_eq!(streamed(text, &options), rendered.text);
+                }
+            }
+        }
+    }
 }
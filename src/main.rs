@@ -11,33 +11,388 @@ extern crate term_size;
 extern crate unicode_segmentation;
 extern crate unicode_width;
 extern crate html2runes;
+extern crate xi_unicode;
+extern crate getopts;
+extern crate atty;
+#[cfg(unix)]
+extern crate termion;
+#[cfg(unix)]
+extern crate libc;
 
 extern crate catmark;
 
+mod pager;
+
 use std::io;
 use std::env;
+use std::process;
+use std::fs;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use ansi_term::Style;
+use getopts::{Matches, Options};
 
-use catmark::OutputKind;
+use catmark::{HyperlinkMode, Numbering, OutputKind, ReferencePlacement, RenderOptions, Rendered};
 
 pub const DEFAULT_COLS: u16 = 80;
 
-pub fn main() {
+fn fail(message: &str) -> ! {
+    eprintln!("catmark: {}", message);
+    process::exit(1);
+}
+
+// value of an option parsed with `FromStr`, if given
+fn parse_opt<T: FromStr<Err = String>>(matches: &Matches, name: &str) -> Option<T> {
+    matches.opt_str(name).map(|value| match value.parse::<T>() {
+        Ok(value) => value,
+        Err(err) => fail(&err),
+    })
+}
+
+fn read_input(path: &str) -> io::Result<String> {
     let mut input = String::new();
-    let mut width = DEFAULT_COLS;
-    if let Some((w, _)) = term_size::dimensions() {
-        width = w as u16;
+    if path == "-" {
+        io::stdin().read_to_string(&mut input)?;
+    } else {
+        File::open(path)?.read_to_string(&mut input)?;
+    }
+    Ok(input)
+}
+
+// line showing the name of the next file, when there are several
+fn separator(name: &str, width: u16, kind: OutputKind) -> String {
+    let line = format!("{:─<1$}", format!("── {} ", name), width as usize);
+    match kind {
+        OutputKind::Plain => line,
+        _ => Style::new().bold().paint(line).to_string(),
     }
-    if let Some(arg1) = env::args().nth(1) {
-        let mut f = File::open(arg1).expect("unable to open file");
-        f.read_to_string(&mut input).expect("unable to read file");
+}
+
+fn options(matches: &Matches) -> RenderOptions {
+    let tty = atty::is(atty::Stream::Stdout);
+    let width = match matches.opt_str("width") {
+        Some(width) => {
+            match width.parse::<u16>() {
+                Ok(width) if width > 0 => width,
+                _ => fail(&format!("invalid width '{}'", width)),
+            }
+        }
+        None => {
+            match term_size::dimensions() {
+                Some((w, _)) if tty => w as u16,
+                _ => DEFAULT_COLS,
+            }
+        }
+    };
+    let color = matches.opt_str("color").unwrap_or_else(|| "auto".to_string());
+    let colored = match &color[..] {
+        _ if matches.opt_present("plain") => false,
+        "always" => true,
+        "never" => false,
+        "auto" => tty && env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()),
+        _ => fail(&format!("invalid color mode '{}'", color)),
+    };
+    let kind = if colored {
+        parse_opt(matches, "colors").unwrap_or_else(OutputKind::detect)
     } else {
-        io::stdin().read_to_string(&mut input).expect(
-            "unable to read stdin",
-        );
+        OutputKind::Plain
+    };
+
+    let mut options = RenderOptions::new(width, kind);
+    if let Some(theme) = matches.opt_str("theme") {
+        options.theme = theme;
+    }
+    options.theme_dir = matches.opt_str("theme-dir").map(PathBuf::from);
+    options.syntax_dir = matches.opt_str("syntax-dir").map(PathBuf::from);
+    options.syntax_dump = matches.opt_str("syntax-dump").map(PathBuf::from);
+    options.default_language = matches.opt_str("default-lang");
+    options.line_numbers = matches.opt_present("line-numbers");
+    options.number_headers = matches.opt_present("number-headers");
+    options.toc_numbers = matches.opt_present("toc-numbers");
+    options.toc = matches.opt_present("toc") || options.toc_numbers;
+    if let Some(mode) = parse_opt::<HyperlinkMode>(matches, "hyperlinks") {
+        options.hyperlinks = mode;
+    } else if !tty {
+        options.hyperlinks = HyperlinkMode::Never;
     }
-    let result = catmark::render_ansi(&input, width, OutputKind::Color);
+    if let Some(placement) = parse_opt::<ReferencePlacement>(matches, "references") {
+        options.references = placement;
+    }
+    if let Some(numbering) = matches.opt_str("numbering") {
+        options.numbering = numbering
+            .split(',')
+            .map(|style| match style.trim().parse::<Numbering>() {
+                Ok(style) => style,
+                Err(err) => fail(&err),
+            })
+            .collect();
+    }
+    options
+}
 
-    println!("{}", result);
+// the documents one after the other, as a single one
+fn render_files(files: &[String], options: &RenderOptions) -> Result<Rendered, String> {
+    let mut all = Rendered {
+        text: String::new(),
+        headings: vec![],
+    };
+    for (i, path) in files.iter().enumerate() {
+        let input = read_input(path).map_err(
+            |err| format!("{}: {}", path, err),
+        )?;
+        if files.len() > 1 {
+            if i > 0 {
+                all.text.push('\n');
+            }
+            all.text.push_str(&separator(path, options.width, options.kind));
+            all.text.push('\n');
+        }
+        let rendered = catmark::render(&input, options).map_err(|err| {
+            format!("{}: {}", path, err)
+        })?;
+        let offset = all.text.lines().count();
+        all.headings.extend(rendered.headings.into_iter().map(|mut heading| {
+            heading.line += offset;
+            heading
+        }));
+        all.text.push_str(&rendered.text);
+    }
+    Ok(all)
+}
+
+// write the documents as they get rendered, without keeping them in memory
+fn stream_files(files: &[String], options: &RenderOptions) -> Result<(), String> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (i, path) in files.iter().enumerate() {
+        let input = read_input(path).map_err(
+            |err| format!("{}: {}", path, err),
+        )?;
+        if files.len() > 1 {
+            let sep = separator(path, options.width, options.kind);
+            let res = if i > 0 {
+                writeln!(out, "\n{}", sep)
+            } else {
+                writeln!(out, "{}", sep)
+            };
+            res.map_err(|err| err.to_string())?;
+        }
+        catmark::render_to(&input, options, &mut out).map_err(|err| {
+            format!("{}: {}", path, err)
+        })?;
+    }
+    out.flush().map_err(|err| err.to_string())
+}
+
+// only the outline of the documents
+fn print_tocs(files: &[String], options: &RenderOptions) -> Result<(), String> {
+    for (i, path) in files.iter().enumerate() {
+        let input = read_input(path).map_err(
+            |err| format!("{}: {}", path, err),
+        )?;
+        if files.len() > 1 {
+            if i > 0 {
+                println!();
+            }
+            println!("{}", separator(path, options.width, options.kind));
+        }
+        let toc = catmark::render_toc(&input, options).map_err(|err| {
+            format!("{}: {}", path, err)
+        })?;
+        print!("{}", toc);
+    }
+    Ok(())
+}
+
+fn modification_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// keep showing the files, rendering them again when they change or when the
+// terminal is resized
+fn watch(files: Vec<String>, mut options: RenderOptions, fixed_width: bool) -> io::Result<()> {
+    let mut times = modification_times(&files);
+    let rendered = match render_files(&files, &options) {
+        Ok(rendered) => rendered,
+        Err(err) => fail(&err),
+    };
+    pager::watch(rendered, move |resized| {
+        let now = modification_times(&files);
+        if !resized && now == times {
+            return None;
+        }
+        times = now;
+        if resized && !fixed_width {
+            if let Some((w, _)) = term_size::dimensions() {
+                options.width = w as u16;
+            }
+        }
+        Some(render_files(&files, &options))
+    })
+}
+
+pub fn main() {
+    let mut opts = Options::new();
+    opts.optopt("w", "width", "width of the output, in columns", "COLS");
+    opts.optopt(
+        "",
+        "color",
+        "when to use colors: auto, always or never",
+        "WHEN",
+    );
+    opts.optopt(
+        "",
+        "colors",
+        "color profile: truecolor, 256, 16, 8, mono or plain",
+        "PROFILE",
+    );
+    opts.optflag("", "plain", "no colors nor styles, same as --color=never");
+    opts.optopt(
+        "t",
+        "theme",
+        "syntax highlighting theme for code blocks",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "theme-dir",
+        "directory of extra .tmTheme files",
+        "DIR",
+    );
+    opts.optflag("", "list-themes", "list the available themes and exit");
+    opts.optopt(
+        "",
+        "syntax-dir",
+        "directory of extra .sublime-syntax files",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "syntax-dump",
+        "file of precompiled syntax definitions",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "default-lang",
+        "language of code blocks whose language can't be detected",
+        "LANG",
+    );
+    opts.optflag("n", "line-numbers", "number the lines of code blocks");
+    opts.optopt(
+        "",
+        "hyperlinks",
+        "make links clickable: always, never or auto",
+        "WHEN",
+    );
+    opts.optopt(
+        "",
+        "references",
+        "where to list link destinations: end or section",
+        "WHERE",
+    );
+    opts.optopt(
+        "",
+        "numbering",
+        "numbering of ordered lists by depth, like 1,a,i (1, a, A, i or I)",
+        "STYLES",
+    );
+    opts.optflag("", "number-headers", "number the headers like 1, 1.1, 1.1.2");
+    opts.optflag(
+        "",
+        "toc",
+        "show a table of contents at the top, or at a [TOC] line",
+    );
+    opts.optflag("", "toc-numbers", "number the table of contents entries");
+    opts.optflag("", "toc-only", "print only the table of contents");
+    opts.optflag("", "no-pager", "print the whole document at once");
+    opts.optflag("", "pager", "page through $PAGER instead of the built-in pager");
+    opts.optflag(
+        "",
+        "watch",
+        "show the files again every time they change",
+    );
+    opts.optflag("h", "help", "print this help and exit");
+    opts.optflag("V", "version", "print the version and exit");
+    let matches = match opts.parse(env::args().skip(1)) {
+        Ok(matches) => matches,
+        Err(err) => fail(&err.to_string()),
+    };
+
+    if matches.opt_present("help") {
+        let brief = "Usage: catmark [options] [FILE...]\n\n\
+                     Prints Markdown files, or the standard input, on the terminal.";
+        print!("{}", opts.usage(brief));
+        return;
+    }
+    if matches.opt_present("version") {
+        println!("catmark {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    let options = options(&matches);
+
+    if matches.opt_present("list-themes") {
+        match catmark::list_themes(&options) {
+            Ok(themes) => {
+                for theme in themes {
+                    println!("{}", theme);
+                }
+            }
+            Err(err) => fail(&err.to_string()),
+        }
+        return;
+    }
+
+    let files = if matches.free.is_empty() {
+        vec!["-".to_string()]
+    } else {
+        matches.free.clone()
+    };
+    if matches.opt_present("toc-only") {
+        if let Err(err) = print_tocs(&files, &options) {
+            fail(&err);
+        }
+        return;
+    }
+    if matches.opt_present("watch") {
+        if files.iter().any(|path| path == "-") {
+            fail("--watch needs files to watch, not the standard input");
+        }
+        if let Err(err) = watch(files, options, matches.opt_present("width")) {
+            fail(&err.to_string());
+        }
+        return;
+    }
+    if matches.opt_present("no-pager") || !atty::is(atty::Stream::Stdout) {
+        if let Err(err) = stream_files(&files, &options) {
+            fail(&err);
+        }
+        return;
+    }
+    let rendered = match render_files(&files, &options) {
+        Ok(rendered) => rendered,
+        Err(err) => fail(&err),
+    };
+    // only page what doesn't fit on the terminal
+    let height = term_size::dimensions().map(|(_, h)| h).unwrap_or(0);
+    let res = if rendered.text.lines().count() < height {
+        // the text already ends with a newline
+        print!("{}", rendered.text);
+        io::stdout().flush()
+    } else if matches.opt_present("pager") {
+        pager::external(&rendered)
+    } else {
+        pager::page(&rendered)
+    };
+    if let Err(err) = res {
+        fail(&err.to_string());
+    }
 }
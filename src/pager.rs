@@ -0,0 +1,426 @@
+// Copyright 2016 Xavier Bestel -  All rights reserved.
+//
+// GPL goes here
+
+//! Showing a rendered document one screen at a time.
+
+use std::env;
+use std::io;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(unix)]
+use libc;
+
+use catmark::Rendered;
+
+/// Pipes the document into `$PAGER`, or `less -R` when it isn't set.
+pub fn external(rendered: &Rendered) -> io::Result<()> {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(&pager);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&pager);
+        command
+    };
+    // keep the colors when $PAGER is a bare `less`
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "R");
+    }
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        match stdin.write_all(rendered.text.as_bytes()) {
+            // the pager was quit before the end of the document
+            Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+            res => res?,
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+/// Shows the document in the built-in pager.
+#[cfg(unix)]
+pub fn page(rendered: &Rendered) -> io::Result<()> {
+    builtin::Pager::new(rendered.clone()).run(|_| None)
+}
+
+/// There's no built-in pager on this platform, use `$PAGER` instead.
+#[cfg(not(unix))]
+pub fn page(rendered: &Rendered) -> io::Result<()> {
+    external(rendered)
+}
+
+/// Shows the document in the built-in pager, replacing it with what `reload`
+/// returns. `reload` is called a few times per second, and is told whether
+/// the terminal was resized since the last call.
+#[cfg(unix)]
+pub fn watch<F>(rendered: Rendered, reload: F) -> io::Result<()>
+where
+    F: FnMut(bool) -> Option<Result<Rendered, String>>,
+{
+    builtin::Pager::new(rendered).run(reload)
+}
+
+/// Prints the document again every time `reload` returns a new one.
+#[cfg(not(unix))]
+pub fn watch<F>(rendered: Rendered, mut reload: F) -> io::Result<()>
+where
+    F: FnMut(bool) -> Option<Result<Rendered, String>>,
+{
+    use std::thread;
+    use std::time::Duration;
+
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b[2J\x1b[H{}", rendered.text)?;
+    stdout.flush()?;
+    loop {
+        thread::sleep(Duration::from_millis(WATCH_INTERVAL));
+        match reload(false) {
+            Some(Ok(rendered)) => write!(stdout, "\x1b[2J\x1b[H{}", rendered.text)?,
+            Some(Err(err)) => writeln!(stdout, "catmark: {}", err)?,
+            None => continue,
+        }
+        stdout.flush()?;
+    }
+}
+
+// milliseconds between checks for changes
+const WATCH_INTERVAL: u64 = 200;
+
+// set by the SIGWINCH handler
+#[cfg(unix)]
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_resize(_: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+mod builtin {
+    use std::cmp;
+    use std::io;
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use termion;
+    use termion::{clear, cursor, style};
+    use termion::event::Key;
+    use termion::input::TermRead;
+    use termion::raw::IntoRawMode;
+    use termion::screen::AlternateScreen;
+
+    use libc;
+
+    use catmark::Rendered;
+
+    use super::{on_resize, RESIZED, WATCH_INTERVAL};
+
+    const HELP: &'static str = "q:quit  /:search  n/p:next/previous header  space/b:page";
+
+    // the text of a line, without its escape sequences
+    fn strip_ansi(line: &str) -> String {
+        let mut plain = String::new();
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            if c != '\x1b' {
+                plain.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('[') => {
+                    while let Some(c) = chars.next() {
+                        if c >= '@' && c <= '~' {
+                            break;
+                        }
+                    }
+                }
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || c == '\\' {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        plain
+    }
+
+    pub struct Pager {
+        rendered: Rendered,
+        plain: Vec<String>,
+        top: usize,
+        search: Option<String>,
+        message: Option<String>,
+    }
+
+    impl Pager {
+        pub fn new(rendered: Rendered) -> Pager {
+            Pager {
+                plain: rendered.text.lines().map(strip_ansi).collect(),
+                rendered: rendered,
+                top: 0,
+                search: None,
+                message: None,
+            }
+        }
+
+        // show another version of the document, staying at the same place in
+        // it: the same distance after the same header if it's still there
+        fn replace(&mut self, rendered: Rendered) {
+            let place = self.rendered
+                .headings
+                .iter()
+                .rev()
+                .find(|heading| heading.line <= self.top)
+                .map(|heading| (heading.title.clone(), self.top - heading.line));
+            *self = Pager {
+                plain: rendered.text.lines().map(strip_ansi).collect(),
+                rendered: rendered,
+                top: self.top,
+                search: self.search.take(),
+                message: None,
+            };
+            if let Some((title, offset)) = place {
+                let line = self.rendered
+                    .headings
+                    .iter()
+                    .find(|heading| heading.title == title)
+                    .map(|heading| heading.line + offset);
+                if let Some(line) = line {
+                    self.top = line;
+                }
+            }
+            let top = self.top;
+            self.scroll_to(top);
+        }
+
+        fn lines(&self) -> usize {
+            self.plain.len()
+        }
+
+        // number of document lines on screen, below them is the status line
+        fn page_height(&self) -> usize {
+            let (_, rows) = termion::terminal_size().unwrap_or((80, 24));
+            if rows > 1 { rows as usize - 1 } else { 1 }
+        }
+
+        fn max_top(&self) -> usize {
+            self.lines().saturating_sub(self.page_height())
+        }
+
+        fn scroll_to(&mut self, line: usize) {
+            self.top = if line > self.max_top() {
+                self.max_top()
+            } else {
+                line
+            };
+        }
+
+        fn scroll_up(&mut self, lines: usize) {
+            self.top = self.top.saturating_sub(lines);
+        }
+
+        fn scroll_down(&mut self, lines: usize) {
+            let top = self.top + lines;
+            self.scroll_to(top);
+        }
+
+        fn next_header(&mut self) {
+            let top = self.top;
+            let next = self.rendered.headings.iter().map(|h| h.line).find(|&line| line > top);
+            match next {
+                Some(line) => self.scroll_to(line),
+                None => self.message = Some("No next header".to_string()),
+            }
+        }
+
+        fn previous_header(&mut self) {
+            let top = self.top;
+            let previous = self.rendered
+                .headings
+                .iter()
+                .rev()
+                .map(|h| h.line)
+                .find(|&line| line < top);
+            match previous {
+                Some(line) => self.scroll_to(line),
+                None => self.message = Some("No previous header".to_string()),
+            }
+        }
+
+        // look for the pattern after the first line of the screen
+        fn find(&mut self, pattern: &str) {
+            let start = self.top + 1;
+            match self.plain
+                .iter()
+                .skip(start)
+                .position(|line| line.contains(pattern)) {
+                Some(pos) => self.scroll_to(start + pos),
+                None => self.message = Some(format!("Pattern not found: {}", pattern)),
+            }
+        }
+
+        fn draw<W: Write>(&self, screen: &mut W, prompt: Option<&str>) -> io::Result<()> {
+            let height = self.page_height();
+            let mut lines = self.rendered.text.lines().skip(self.top);
+            for row in 0..height {
+                write!(
+                    screen,
+                    "{}{}",
+                    cursor::Goto(1, row as u16 + 1),
+                    clear::CurrentLine
+                )?;
+                if let Some(line) = lines.next() {
+                    write!(screen, "{}{}", line, style::Reset)?;
+                }
+            }
+            write!(
+                screen,
+                "{}{}",
+                cursor::Goto(1, height as u16 + 1),
+                clear::CurrentLine
+            )?;
+            match prompt {
+                Some(prompt) => write!(screen, "/{}", prompt)?,
+                None => {
+                    let status = match self.message {
+                        Some(ref message) => message.clone(),
+                        None => {
+                            let bottom = cmp::min(self.top + height, self.lines());
+                            format!(
+                                "lines {}-{}/{} {}%  {}",
+                                self.top + 1,
+                                bottom,
+                                self.lines(),
+                                bottom * 100 / cmp::max(self.lines(), 1),
+                                HELP
+                            )
+                        }
+                    };
+                    let (cols, _) = termion::terminal_size().unwrap_or((80, 24));
+                    let status: String = status.chars().take(cols as usize).collect();
+                    write!(screen, "{}{}{}", style::Invert, status, style::Reset)?;
+                }
+            }
+            screen.flush()
+        }
+
+        // handle a key, telling whether to go on
+        fn on_key(&mut self, key: Key, prompt: &mut Option<String>) -> bool {
+            if let Some(mut pattern) = prompt.take() {
+                match key {
+                    Key::Char('\n') => {
+                        if pattern.is_empty() {
+                            pattern = self.search.clone().unwrap_or_default();
+                        }
+                        if !pattern.is_empty() {
+                            self.find(&pattern);
+                            self.search = Some(pattern);
+                        }
+                    }
+                    Key::Esc | Key::Ctrl('c') => {}
+                    Key::Backspace => {
+                        pattern.pop();
+                        *prompt = Some(pattern);
+                    }
+                    Key::Char(c) => {
+                        pattern.push(c);
+                        *prompt = Some(pattern);
+                    }
+                    _ => *prompt = Some(pattern),
+                }
+                return true;
+            }
+            self.message = None;
+            let page = self.page_height();
+            match key {
+                Key::Char('q') | Key::Char('Q') | Key::Esc | Key::Ctrl('c') => return false,
+                Key::Char('j') | Key::Char('\n') | Key::Down => self.scroll_down(1),
+                Key::Char('k') | Key::Up => self.scroll_up(1),
+                Key::Char(' ') | Key::Char('f') | Key::PageDown => self.scroll_down(page),
+                Key::Char('b') | Key::PageUp => self.scroll_up(page),
+                Key::Char('d') => self.scroll_down(page / 2),
+                Key::Char('u') => self.scroll_up(page / 2),
+                Key::Char('g') | Key::Home => self.top = 0,
+                Key::Char('G') | Key::End => {
+                    let top = self.max_top();
+                    self.top = top;
+                }
+                Key::Char('n') => self.next_header(),
+                Key::Char('p') => self.previous_header(),
+                Key::Char('/') => *prompt = Some(String::new()),
+                _ => {}
+            }
+            true
+        }
+
+        pub fn run<F>(&mut self, mut reload: F) -> io::Result<()>
+        where
+            F: FnMut(bool) -> Option<Result<Rendered, String>>,
+        {
+            // keys come from the terminal, as stdin may be the document
+            let tty = termion::get_tty()?;
+            let (sender, keys) = mpsc::channel();
+            thread::spawn(move || for key in tty.keys() {
+                if sender.send(key).is_err() {
+                    break;
+                }
+            });
+            unsafe {
+                let handler: extern "C" fn(libc::c_int) = on_resize;
+                libc::signal(libc::SIGWINCH, handler as libc::sighandler_t);
+            }
+
+            let mut screen = AlternateScreen::from(io::stdout().into_raw_mode()?);
+            write!(screen, "{}", cursor::Hide)?;
+            let mut prompt: Option<String> = None;
+            self.draw(&mut screen, None)?;
+            loop {
+                let key = keys.recv_timeout(Duration::from_millis(WATCH_INTERVAL));
+                let resized = RESIZED.swap(false, Ordering::SeqCst);
+                let mut changed = resized;
+                match reload(resized) {
+                    Some(Ok(rendered)) => {
+                        self.replace(rendered);
+                        changed = true;
+                    }
+                    Some(Err(err)) => {
+                        self.message = Some(err);
+                        changed = true;
+                    }
+                    None => {}
+                }
+                match key {
+                    Ok(key) => {
+                        if !self.on_key(key?, &mut prompt) {
+                            break;
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if !changed {
+                            continue;
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+                self.draw(&mut screen, prompt.as_ref().map(|p| &p[..]))?;
+            }
+            write!(screen, "{}", cursor::Show)?;
+            screen.flush()
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2016 Xavier Bestel -  All rights reserved.
+//
+// GPL goes here
+
+//! Read-only view of a laid out document.
+
+use std::ops::Range;
+
+use super::dombox::{BoxKind, DomBox, DomColor};
+
+/// What a box of a laid out document is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutKind {
+    /// Some text, on a single line.
+    Text,
+    /// A line break.
+    Break,
+    /// A line of inline boxes.
+    Line,
+    /// Emphasis, strong text, inline code or a link.
+    Inline,
+    /// A paragraph, a code block, a block quote, a list item...
+    Block,
+    /// A header, of the given level.
+    Header(u8),
+    /// A list, numbered from the given number when ordered.
+    List(Option<u16>),
+    /// The bullet or number of a list item.
+    ListBullet,
+    /// A table, made of columns.
+    Table,
+    /// A column of a table, made of cells.
+    TableColumn,
+    /// A cell of a table.
+    TableCell,
+    /// An image.
+    Image,
+}
+
+/// A color, as asked for by the document.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Color {
+    /// Index in the terminal palette, the first 16 being the basic colors.
+    Indexed(u8),
+    /// 24-bit color.
+    Rgb(u8, u8, u8),
+}
+
+/// Style of a box, which its text is shown with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutStyle {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub italic: bool,
+    /// Target of the link the box is part of.
+    pub link: Option<String>,
+}
+
+/// Area of the terminal, in columns and lines from the top left corner of
+/// the document.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    /// Whether the cell at `x`, `y` is inside the area.
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+}
+
+/// Widths of the borders around a box.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Edges {
+    pub top: u16,
+    pub bottom: u16,
+    pub left: u16,
+    pub right: u16,
+}
+
+/// A box of a laid out document, see `Document::tree`.
+#[derive(Debug, Copy, Clone)]
+pub struct LayoutBox<'d, 'a: 'd> {
+    dombox: &'d DomBox<'a>,
+}
+
+// view of a laid out tree
+pub fn tree<'d, 'a>(root: &'d DomBox<'a>) -> LayoutBox<'d, 'a> {
+    LayoutBox { dombox: root }
+}
+
+impl<'d, 'a> LayoutBox<'d, 'a> {
+    pub fn kind(&self) -> LayoutKind {
+        match self.dombox.kind {
+            BoxKind::Text(_) => LayoutKind::Text,
+            BoxKind::Break => LayoutKind::Break,
+            BoxKind::InlineContainer => LayoutKind::Line,
+            BoxKind::Inline => LayoutKind::Inline,
+            BoxKind::Block => LayoutKind::Block,
+            BoxKind::Header(level) => LayoutKind::Header(level),
+            BoxKind::List(start) => LayoutKind::List(start),
+            BoxKind::ListBullet => LayoutKind::ListBullet,
+            BoxKind::Table(_) => LayoutKind::Table,
+            BoxKind::TableColumn => LayoutKind::TableColumn,
+            BoxKind::TableItem => LayoutKind::TableCell,
+            BoxKind::Image => LayoutKind::Image,
+        }
+    }
+
+    /// The text of a `Text` box.
+    pub fn text(&self) -> Option<&'d str> {
+        match self.dombox.kind {
+            BoxKind::Text(ref text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn style(&self) -> LayoutStyle {
+        let style = &self.dombox.style;
+        LayoutStyle {
+            fg: color(&style.fg),
+            bg: color(&style.bg),
+            bold: style.bold,
+            underline: style.underline,
+            strikethrough: style.strikethrough,
+            italic: style.italic,
+            link: style.link.clone(),
+        }
+    }
+
+    /// Area of the content, inside the borders.
+    pub fn content(&self) -> Rect {
+        let content = self.dombox.size.content;
+        Rect {
+            x: content.x,
+            y: content.y,
+            width: content.w,
+            height: content.h,
+        }
+    }
+
+    pub fn border(&self) -> Edges {
+        let border = self.dombox.size.border;
+        Edges {
+            top: border.top,
+            bottom: border.bottom,
+            left: border.left,
+            right: border.right,
+        }
+    }
+
+    /// Area of the content and the borders.
+    pub fn area(&self) -> Rect {
+        let size = &self.dombox.size;
+        Rect {
+            x: size.content.x - size.border.left,
+            y: size.content.y - size.border.top,
+            width: size.width_plus_border(),
+            height: size.height_plus_border(),
+        }
+    }
+
+    /// Bytes of the Markdown text the box comes from. Boxes which aren't
+    /// from the text, like list bullets or the references section, have
+    /// none.
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.dombox.span.clone()
+    }
+
+    pub fn children(&self) -> Vec<LayoutBox<'d, 'a>> {
+        self.dombox.children.iter().map(tree).collect()
+    }
+
+    /// The boxes containing the cell at `x`, `y`, from this one to the
+    /// innermost one. Empty when this box doesn't contain it.
+    pub fn boxes_at(&self, x: u16, y: u16) -> Vec<LayoutBox<'d, 'a>> {
+        let mut boxes = vec![];
+        let mut current = *self;
+        while current.area().contains(x, y) {
+            boxes.push(current);
+            match current.children().into_iter().find(|child| child.area().contains(x, y)) {
+                Some(child) => current = child,
+                None => break,
+            }
+        }
+        boxes
+    }
+}
+
+fn color(color: &DomColor) -> Option<Color> {
+    match color.index() {
+        Some(idx) => Some(Color::Indexed(idx)),
+        None => color.rgb().map(|(r, g, b)| Color::Rgb(r, g, b)),
+    }
+}
+